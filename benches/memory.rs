@@ -0,0 +1,197 @@
+// Copyright (C) 2016  Michael Dunsmuir
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// How much memory a scanned tree takes per entry, in the way it's stored now
+// and in the way it used to be (a whole `PathBuf` and `fs::Metadata` per
+// entry and a `BTreeMap` per directory), which is rebuilt below from the
+// same directory for comparison.
+//
+// Run with `cargo bench`. It scans a made-up tree in the temp directory
+// unless `DREDGE_BENCH_DIR` says to scan somewhere real.
+
+extern crate dredge;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dredge::{Scanner, Size};
+
+/// Number of directories in the made-up tree, and files in each.
+const DIRS: usize = 200;
+const FILES: usize = 250;
+
+// keeps track of how much is allocated right now
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::SeqCst);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// the tree as it used to be, cut down to what a plain scan fills in
+#[allow(dead_code)]
+enum OldTree {
+    Dir {
+        contents: BTreeMap<OsString, OldTree>,
+        path: PathBuf,
+        metadata: fs::Metadata,
+        total_size: Size,
+        pending: usize,
+        errors: usize,
+    },
+
+    File {
+        path: PathBuf,
+        metadata: fs::Metadata,
+        share: usize,
+    },
+
+    Symlink {
+        path: PathBuf,
+        metadata: fs::Metadata,
+        target: PathBuf,
+        cycle: bool,
+    },
+
+    Bad {
+        path: PathBuf,
+        error: io::ErrorKind,
+    },
+}
+
+impl OldTree {
+
+    fn read(path: PathBuf) -> Self {
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => return OldTree::Bad { path, error: e.kind() },
+        };
+
+        if metadata.is_dir() {
+            let contents = fs::read_dir(&path).map(|entries|
+                entries.filter_map(|entry| entry.ok() )
+                    .map(|entry| (entry.file_name(), OldTree::read(entry.path())) )
+                    .collect()
+            ).unwrap_or_default();
+
+            OldTree::Dir {
+                contents,
+                path,
+                metadata,
+                total_size: Size::default(),
+                pending: 0,
+                errors: 0,
+            }
+
+        } else if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&path).unwrap_or_default();
+            OldTree::Symlink { path, metadata, target, cycle: false }
+
+        } else {
+            OldTree::File { path, metadata, share: 0 }
+        }
+    }
+
+    fn count(&self) -> usize {
+        match *self {
+            OldTree::Dir { ref contents, .. } =>
+                1 + contents.values().map(|tree| tree.count() ).sum::<usize>(),
+
+            _ => 1,
+        }
+    }
+}
+
+// the bytes still allocated once `build` is done, and what it built
+fn measure<T, F: FnOnce() -> T>(build: F) -> (T, usize) {
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let built = build();
+    (built, ALLOCATED.load(Ordering::SeqCst).saturating_sub(before))
+}
+
+// a tree with some names that turn up in every directory and some that
+// don't, like most real ones
+fn make_tree() -> io::Result<PathBuf> {
+    let root = env::temp_dir().join("dredge-memory-bench");
+    let _ = fs::remove_dir_all(&root);
+
+    for dir in 0..DIRS {
+        let path = root.join(format!("module-{}", dir));
+        fs::create_dir_all(&path)?;
+
+        for file in 0..FILES {
+            let name = if file % 2 == 0 {
+                format!("part-{}.rs", file)
+            } else {
+                format!("output-{}-{}.log", dir, file)
+            };
+
+            fs::File::create(path.join(name))?;
+        }
+    }
+
+    Ok(root)
+}
+
+fn report(label: &str, bytes: usize, entries: usize) {
+    println!("{:8} {:>12} bytes {:>8.1} per entry", label, bytes, bytes as f64 / entries as f64);
+}
+
+fn main() {
+    let (root, made_up) = match env::var_os("DREDGE_BENCH_DIR") {
+        Some(dir) => (PathBuf::from(dir), false),
+        None => (make_tree().expect("couldn't make a tree to scan"), true),
+    };
+
+    let (old, old_bytes) = measure(|| OldTree::read(root.clone()) );
+    let entries = old.count();
+    drop(old);
+
+    let (fst, new_bytes) = measure(|| Scanner::new().threads(1).scan(&root) );
+    let fst = fst.expect("couldn't read the root directory");
+
+    println!("{} entries in {}", entries, root.display());
+    report("before", old_bytes, entries);
+    report("after", new_bytes, entries);
+    drop(fst);
+
+    if made_up {
+        let _ = fs::remove_dir_all(Path::new(&root));
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright (C) 2016  Michael Dunsmuir
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate std;
+
+use std::cmp::Ordering;
+use std::ffi::{OsStr, OsString};
+
+use super::fstree::{Change, FSTree, Listing, Node};
+
+/// An entry in the merged view of two scans of the same tree: an earlier
+/// one and a later one. Entries are matched up by name, so the roots don't
+/// have to be in the same place (the scans might be from different
+/// machines). Nothing is copied, the trees are just walked side by side.
+#[derive(Clone, Copy)]
+pub struct DiffNode<'t> {
+    old: Option<Node<'t>>,
+    new: Option<Node<'t>>,
+}
+
+impl<'t> DiffNode<'t> {
+
+    /// The roots of `old` and `new`, compared.
+    pub fn new(old: &'t FSTree, new: &'t FSTree) -> Self {
+        DiffNode { old: Some(old.root()), new: Some(new.root()) }
+    }
+
+    pub fn change(&self) -> Change {
+        Change {
+            old: self.old.map(|node| node.size().unwrap_or_default() ),
+            new: self.new.map(|node| node.size().unwrap_or_default() ),
+        }
+    }
+
+    /// Whether there's anything inside this entry in either scan. Something
+    /// that used to be a directory and isn't any more can still be looked
+    /// into, to see everything that went with it.
+    pub fn has_contents(&self) -> bool {
+        self.old.map(|node| node.has_contents() ).unwrap_or(false)
+            || self.new.map(|node| node.has_contents() ).unwrap_or(false)
+    }
+
+    /// Everything in here in either scan, in order of name.
+    pub fn children(&self) -> Vec<DiffNode<'t>> {
+        let mut old = self.old.filter(|node| node.has_contents() ).into_iter()
+            .flat_map(|node| node.children() )
+            .peekable();
+
+        let mut new = self.new.filter(|node| node.has_contents() ).into_iter()
+            .flat_map(|node| node.children() )
+            .peekable();
+
+        let mut children = Vec::new();
+
+        // both sides are already sorted by name, so they can just be zipped
+        // together
+        loop {
+            let order = match (old.peek(), new.peek()) {
+                (Some(a), Some(b)) => a.name().cmp(b.name()),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break,
+            };
+
+            children.push(match order {
+                Ordering::Less => DiffNode { old: old.next(), new: None },
+                Ordering::Greater => DiffNode { old: None, new: new.next() },
+                Ordering::Equal => DiffNode { old: old.next(), new: new.next() },
+            });
+        }
+
+        children
+    }
+
+    pub fn entry(&self, name: &OsStr) -> Option<DiffNode<'t>> {
+        let old = self.old.and_then(|node| node.entry(name) );
+        let new = self.new.and_then(|node| node.entry(name) );
+
+        if old.is_none() && new.is_none() {
+            None
+        } else {
+            Some(DiffNode { old, new })
+        }
+    }
+
+    pub fn entries(&self, names: &[OsString]) -> Option<DiffNode<'t>> {
+        names.iter().try_fold(*self, |node, name| node.entry(name) )
+    }
+
+    /// Like `Node::list`, but with the `change` filled in for everything.
+    /// Entries that have gone are listed as they were.
+    pub fn list(&self) -> Option<Vec<Listing>> {
+        if !self.has_contents() {
+            return None;
+        }
+
+        Some(self.children().into_iter().map(|diff| {
+            // anything that's still there is shown as it is now
+            let node = diff.new.or(diff.old).unwrap();
+            let change = diff.change();
+
+            Listing {
+                name: node.name().to_os_string(),
+                size: change.new.unwrap_or_default(),
+                kind: node.kind(),
+                is_dir: diff.has_contents(),
+                symlink_target: node.target().map(|target| target.as_os_str().to_os_string() ),
+
+                scanning: diff.new.map(|node| node.is_scanning() ).unwrap_or(false),
+                links: node.links().unwrap_or(1),
+                error: node.error(),
+                change: Some(change),
+            }
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::fstree::{Contents, Entry, EntryKind, Size, SizeMode};
+    use std::path::PathBuf;
+
+    fn size(n: u64) -> Size {
+        Size { disk: n, apparent: n }
+    }
+
+    // a tree of files with the given sizes, and directories holding a file
+    // each
+    fn tree(files: &[(&str, u64)], dirs: &[(&str, u64)]) -> FSTree {
+        let top = Entry::new(OsString::from("data"), EntryKind::Dir, Size::default());
+        let mut fst = FSTree::from_entry(PathBuf::from("/data"), top);
+
+        let entries = files.iter()
+            .map(|&(name, n)| Entry::new(OsString::from(name), EntryKind::File, size(n)) )
+            .chain(dirs.iter().map(|&(name, _)| Entry::new(OsString::from(name), EntryKind::Dir, Size::default()) ))
+            .collect();
+        fst.graft(&[], Ok(Contents::from_entries(entries)));
+
+        for &(name, n) in dirs {
+            let file = Entry::new(OsString::from("file"), EntryKind::File, size(n));
+            fst.graft(&[OsString::from(name)], Ok(Contents::from_entries(vec![file])));
+        }
+
+        fst
+    }
+
+    #[test]
+    fn added_and_removed() {
+        let old = tree(&[("same", 10), ("grown", 10), ("shrunk", 50), ("removed", 30)], &[("gone", 40)]);
+        let new = tree(&[("same", 10), ("grown", 100), ("shrunk", 20), ("added", 60)], &[("dir", 5)]);
+        let diff = DiffNode::new(&old, &new);
+
+        let names: Vec<OsString> = diff.children().iter()
+            .map(|child| child.new.or(child.old).unwrap().name().to_os_string() )
+            .collect();
+        assert_eq!(names, ["added", "dir", "gone", "grown", "removed", "same", "shrunk"]);
+
+        let root = diff.change();
+        assert_eq!(root, Change { old: Some(size(140)), new: Some(size(195)) });
+        assert_eq!(root.delta(SizeMode::Disk), 55);
+
+        let removed = diff.entry(OsStr::new("removed")).unwrap().change();
+        assert!(removed.is_removed() && !removed.is_added());
+        assert_eq!(removed.delta(SizeMode::Apparent), -30);
+
+        let added = diff.entry(OsStr::new("added")).unwrap().change();
+        assert!(added.is_added() && !added.is_removed());
+        assert_eq!(added.delta(SizeMode::Apparent), 60);
+
+        // what went with a directory that's gone can still be looked at
+        let gone = diff.entries(&[OsString::from("gone"), OsString::from("file")]).unwrap();
+        assert_eq!(gone.change(), Change { old: Some(size(40)), new: None });
+        assert!(diff.entry(OsStr::new("nowhere")).is_none());
+    }
+
+    #[test]
+    fn by_growth() {
+        let old = tree(&[("same", 10), ("grown", 10), ("shrunk", 50), ("removed", 30)], &[("gone", 40)]);
+        let new = tree(&[("same", 10), ("grown", 100), ("shrunk", 20), ("added", 60)], &[("dir", 5)]);
+
+        // the way the browser sorts them, biggest growth first
+        let mut listing = DiffNode::new(&old, &new).list().unwrap();
+        listing.sort_by_key(|listing| std::cmp::Reverse(listing.change.unwrap().delta(SizeMode::Disk)) );
+
+        let order: Vec<(&OsStr, i64)> = listing.iter()
+            .map(|listing| (&listing.name[..], listing.change.unwrap().delta(SizeMode::Disk)) )
+            .collect();
+        assert_eq!(order, [
+            (OsStr::new("grown"), 90),
+            (OsStr::new("added"), 60),
+            (OsStr::new("dir"), 5),
+            (OsStr::new("same"), 0),
+            (OsStr::new("removed"), -30),
+            (OsStr::new("shrunk"), -30),
+            (OsStr::new("gone"), -40),
+        ]);
+
+        // still listed, with nothing left of it
+        let removed = listing.iter().find(|listing| listing.name == "gone" ).unwrap();
+        assert!(removed.is_dir);
+        assert_eq!(removed.size, Size::default());
+    }
+}
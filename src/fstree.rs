@@ -15,304 +15,1682 @@
 
 extern crate std;
 
-use std::collections::BTreeMap;
-use std::ffi::OsString;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::{OsStr, OsString};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::{Add, AddAssign, Div, Sub, SubAssign};
 use std::path::{Path, PathBuf};
 use std::fs;
 use super::os;
+use super::scan::{ScanOptions, Scanner};
 
-pub type Listing = (OsString, u64, bool, Option<OsString>);
+/// Which of the two sizes we keep for everything is the one to go by.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SizeMode {
+    /// The space actually allocated on disk.
+    Disk,
 
-pub struct Contents(BTreeMap<OsString, FSTree>);
+    /// The size as reported by e.g. `ls -l`, which can be larger (sparse
+    /// files, compression) or smaller (block rounding) than what's on disk.
+    Apparent,
+}
 
-pub enum FSTree {
-    Root {
-        contents: Contents,
-        path: PathBuf,
-        total_size: u64,
-    },
+/// Anything allocated at less than this fraction of its apparent size counts
+/// as sparse (or compressed, which looks the same from out here).
+pub const SPARSE_RATIO: f64 = 0.5;
+
+/// The size of an entry, or the total size of a directory's contents, in
+/// both of the ways we know how to measure it. They're kept side by side so
+/// that switching between them doesn't mean rescanning.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Size {
+    pub disk: u64,
+    pub apparent: u64,
+}
+
+impl Size {
+
+    pub fn from_metadata(metadata: &fs::Metadata) -> Self {
+        Size {
+            disk: os::size(metadata),
+            apparent: os::apparent_size(metadata),
+        }
+    }
+
+    pub fn get(&self, mode: SizeMode) -> u64 {
+        match mode {
+            SizeMode::Disk => self.disk,
+            SizeMode::Apparent => self.apparent,
+        }
+    }
+
+    /// How much is allocated on disk for every byte of apparent size. Below
+    /// one means sparse or compressed, above one means lots of slack in the
+    /// last block (or metadata). Empty things don't have a ratio.
+    pub fn ratio(&self) -> Option<f64> {
+        if self.apparent == 0 {
+            None
+        } else {
+            Some(self.disk as f64 / self.apparent as f64)
+        }
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        self.ratio().map(|ratio| ratio < SPARSE_RATIO ).unwrap_or(false)
+    }
+}
+
+impl Add for Size {
+    type Output = Size;
+
+    fn add(self, other: Size) -> Size {
+        Size {
+            disk: self.disk + other.disk,
+            apparent: self.apparent + other.apparent,
+        }
+    }
+}
+
+impl Sub for Size {
+    type Output = Size;
+
+    fn sub(self, other: Size) -> Size {
+        Size {
+            disk: self.disk - other.disk,
+            apparent: self.apparent - other.apparent,
+        }
+    }
+}
+
+impl Div<u64> for Size {
+    type Output = Size;
+
+    fn div(self, divisor: u64) -> Size {
+        Size {
+            disk: self.disk / divisor,
+            apparent: self.apparent / divisor,
+        }
+    }
+}
+
+impl AddAssign for Size {
+    fn add_assign(&mut self, other: Size) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for Size {
+    fn sub_assign(&mut self, other: Size) {
+        *self = *self - other;
+    }
+}
+
+impl std::iter::Sum for Size {
+    fn sum<I: Iterator<Item = Size>>(iter: I) -> Size {
+        iter.fold(Size::default(), Add::add)
+    }
+}
+
+/// What sort of entry a `Listing` is for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntryKind {
+    Dir,
+    File,
+    Symlink,
+    Linked,
+    Cycle,
+    MountPoint,
+    Excluded,
+    Special(SpecialKind),
+    Bad,
+}
+
+/// The kinds of file that are neither regular files, directories nor
+/// symlinks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SpecialKind {
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+/// One line's worth of information about a directory entry, for display.
+pub struct Listing {
+    pub name: OsString,
+    pub size: Size,
+    pub kind: EntryKind,
+    pub is_dir: bool,
+    pub symlink_target: Option<OsString>,
+
+    /// Whether the scanner is still working somewhere inside this entry.
+    pub scanning: bool,
+
+    /// The number of hard links to this entry, if it's a file.
+    pub links: u64,
+
+    /// What went wrong, if this entry couldn't be read.
+    pub error: Option<io::ErrorKind>,
+
+    /// How the entry differs from an earlier scan, when comparing two.
+    pub change: Option<Change>,
+}
+
+/// An entry's size in two scans of the same tree. It's missing from
+/// whichever one the entry isn't in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Change {
+    pub old: Option<Size>,
+    pub new: Option<Size>,
+}
+
+impl Change {
+
+    /// How much bigger the entry has got (negative if it's shrunk).
+    pub fn delta(&self, mode: SizeMode) -> i64 {
+        let size = |size: Option<Size>| size.unwrap_or_default().get(mode) as i64;
+        size(self.new) - size(self.old)
+    }
+
+    pub fn is_added(&self) -> bool {
+        self.old.is_none()
+    }
+
+    pub fn is_removed(&self) -> bool {
+        self.new.is_none()
+    }
+}
 
+/// How much of a file's size is counted against a particular path. This
+/// only matters for files with more than one hard link, which would
+/// otherwise be counted once for every link.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Share {
+    /// All of it: this is the only link, or the first one the scan saw.
+    Whole,
+
+    /// None of it, because some other link already counted it.
+    Duplicate,
+
+    /// An even share with the other links (the value is the link count).
+    Split(u64),
+}
+
+/// Which link gets to count a multiply-linked file's size.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LinkAttribution {
+    /// Whichever link the scan happens across first.
+    First,
+
+    /// All of them, evenly.
+    Split,
+}
+
+/// Where an entry lives in its tree.
+type Id = u32;
+
+/// The top of every tree, which is always the first thing in it.
+const TOP: Id = 0;
+
+/// The parent of the top of a tree.
+const NO_PARENT: Id = Id::MAX;
+
+/// Stands in for the modification time of anything we couldn't get one for.
+const NO_MTIME: i64 = i64::MIN;
+
+// the owner of anything we don't know the owner of
+const NO_OWNER: u32 = u32::MAX;
+
+/// The things each entry contributes to the directories above it: its
+/// counted size, pending directories and errors.
+type Tally = (Size, u32, u32);
+
+// everything we keep about an entry besides its name, size and place in the
+// tree
+#[derive(Clone)]
+enum Kind {
+    // `pending` is the number of directories in here (counting this one)
+    // that the scanner hasn't read yet, and `errors` is the number of `Bad`
+    // entries anywhere inside it. `children` are kept sorted by name.
+    //
+    // `linked` is set for a symlink to a directory, which we were asked to
+    // follow. Whatever's in there counts towards its own total but not
+    // towards anything above it, since the bytes really live somewhere else
     Dir {
-        contents: Contents,
-        path: PathBuf,
-        metadata: fs::Metadata,
-        total_size: u64,
+        children: Box<[Id]>,
+        pending: u32,
+        errors: u32,
+        linked: bool,
     },
 
     File {
-        path: PathBuf,
-        metadata: fs::Metadata,
+        links: u32,
+        share: Share,
     },
 
+    // `cycle` is set if we'd have followed this, except that it leads back
+    // to a directory we're already inside
     Symlink {
-        path: PathBuf,
-        metadata: fs::Metadata,
-        target: PathBuf,
+        cycle: bool,
     },
 
-    Bad,
+    // a directory on a different filesystem from the root, which we were
+    // asked not to go into
+    MountPoint,
+
+    // a named pipe, socket or device node
+    Special(SpecialKind),
+
+    // something the exclude patterns told us to skip, which we only keep
+    // around so it can be shown
+    Excluded,
+
+    // something we couldn't read, and why
+    Bad(io::ErrorKind),
 }
 
-impl Contents {
+impl Kind {
 
-    fn from_path<P: AsRef<Path>>(path: P) -> Option<Contents> {
-        fs::read_dir(path).map(|r_entries| // map over the directory entries
-            Contents(r_entries.filter_map(|r_entry| // they are in Results
-                r_entry.map(|entry|
-                    (
-                        entry.file_name(),
-                        FSTree::from_dir_entry(entry),
-                    )
-                ).ok()
-            ).collect())
-        ).ok()
+    fn unread_dir(linked: bool) -> Self {
+        Kind::Dir {
+            children: Box::new([]),
+            pending: 1,
+            errors: 0,
+            linked,
+        }
     }
 
-    fn size(&self) -> u64 {
-        self.get_map().values()
-           .map(|fst| fst.size().unwrap_or(0) )
-           .fold(0, |a, b| a + b )
+    fn entry_kind(&self) -> EntryKind {
+        match *self {
+            Kind::Dir { linked: false, .. } => EntryKind::Dir,
+            Kind::Dir { linked: true, .. } => EntryKind::Linked,
+            Kind::File { .. } => EntryKind::File,
+            Kind::Symlink { cycle: false } => EntryKind::Symlink,
+            Kind::Symlink { cycle: true } => EntryKind::Cycle,
+            Kind::MountPoint => EntryKind::MountPoint,
+            Kind::Special(kind) => EntryKind::Special(kind),
+            Kind::Excluded => EntryKind::Excluded,
+            Kind::Bad(_) => EntryKind::Bad,
+        }
     }
 
-    fn get_map(&self) -> &BTreeMap<OsString, FSTree> {
-        let Contents(ref map) = *self;
-        map
+    fn is_dir(&self) -> bool {
+        matches!(*self, Kind::Dir { .. })
     }
 
-    fn get_map_mut(&mut self) -> &mut BTreeMap<OsString, FSTree> {
-        let Contents(ref mut map) = *self;
-        map
+    fn is_linked(&self) -> bool {
+        if let Kind::Dir { linked, .. } = *self { linked } else { false }
     }
-}
 
-macro_rules! fst_accessor {
-    ( $name:ident,
-      $return_type:ty, 
-      $( $variant:ident ),+
-    ) => {
-        pub fn $name(&self) -> Option<&$return_type> {
-            match *self {
-                $(
-                    FSTree::$variant { ref $name, .. } => Some($name),
-                )+
-                _ => None,
-            }
+    // how much of `size` to show for an entry of this kind. `Bad` entries
+    // and the like don't have any reportable size, hence the `Option`
+    fn shown_size(&self, size: Size) -> Option<Size> {
+        match *self {
+            Kind::File { share: Share::Duplicate, .. } => Some(Size::default()),
+            Kind::File { share: Share::Split(links), .. } => Some(size / links),
+            Kind::MountPoint | Kind::Excluded | Kind::Bad(_) => None,
+            _ => Some(size),
         }
-    };
-
-    (mut: $name:ident,
-     $field:ident,
-     $return_type:ty,
-     $( $variant:ident ),*
-    ) => {
-        fn $name(&mut self) -> Option<&mut $return_type> {
-            match *self {
-                $(
-                    FSTree::$variant { ref mut $field, .. } => Some($field),
-                )+
-                _ => None,
-            }
+    }
+
+    // how much of `size` counts towards the directories above, which is
+    // none of it for a followed symlink
+    fn counted_size(&self, size: Size) -> Size {
+        if self.is_linked() {
+            Size::default()
+        } else {
+            self.shown_size(size).unwrap_or_default()
         }
     }
-}
 
-macro_rules! variant_checker {
-    ( $name:ident, $variant:ident ) => {
-        pub fn $name(&self) -> bool {
-            if let FSTree::$variant { .. } = *self {
-                true
-            } else {
-                false
-            }
+    fn pending(&self) -> u32 {
+        if let Kind::Dir { pending, .. } = *self { pending } else { 0 }
+    }
+
+    // the number of `Bad` entries in this one, including itself
+    fn error_count(&self) -> u32 {
+        match *self {
+            Kind::Dir { errors, .. } => errors,
+            Kind::Bad(_) => 1,
+            _ => 0,
         }
     }
+
+    fn children(&self) -> &[Id] {
+        if let Kind::Dir { ref children, .. } = *self { children } else { &[] }
+    }
 }
 
-impl FSTree {
+/// A single directory entry as read from disk, before it goes into a tree.
+/// Directories come without anything inside them; filling them in is the
+/// `Scanner`'s job.
+pub struct Entry {
+    name: OsString,
+    size: Size,
+    kind: Kind,
+    mtime: i64,
+    owner: u32,
+
+    // (device, inode), of the directory itself for a followed symlink
+    inode: (u64, u64),
+    target: Option<PathBuf>,
+}
+
+impl Entry {
 
-    fst_accessor!(contents, Contents, Root, Dir);
-    fst_accessor!(path, std::path::PathBuf, Root, Dir, File, Symlink);
-    fst_accessor!(metadata, std::fs::Metadata, Dir, File, Symlink);
-    fst_accessor!(total_size, u64, Root, Dir);
+    fn blank(name: OsString, kind: Kind) -> Self {
+        Entry {
+            name,
+            size: Size::default(),
+            kind,
+            mtime: NO_MTIME,
+            owner: NO_OWNER,
+            inode: (0, 0),
+            target: None,
+        }
+    }
 
-    fst_accessor!(mut: contents_mut, contents, Contents, Root, Dir);
-    fst_accessor!(mut: total_size_mut, total_size, u64, Root, Dir);
+    /// An entry that didn't come from the disk, e.g. one read back from a
+    /// saved scan. Directories and followed symlinks start out unread, and
+    /// their size is whatever turns up inside them, so `size` is ignored.
+    pub fn new(name: OsString, kind: EntryKind, size: Size) -> Self {
+        let kind = match kind {
+            EntryKind::Dir => Kind::unread_dir(false),
+            EntryKind::Linked => Kind::unread_dir(true),
+            EntryKind::File => Kind::File { links: 1, share: Share::Whole },
+            EntryKind::Symlink => Kind::Symlink { cycle: false },
+            EntryKind::Cycle => Kind::Symlink { cycle: true },
+            EntryKind::MountPoint => Kind::MountPoint,
+            EntryKind::Excluded => Kind::Excluded,
+            EntryKind::Special(kind) => Kind::Special(kind),
+            EntryKind::Bad => Kind::Bad(io::ErrorKind::Other),
+        };
 
-    variant_checker!(is_root, Root);
-    variant_checker!(is_dir, Dir);
-    variant_checker!(is_file, File);
-    variant_checker!(is_symlink, Symlink);
-    variant_checker!(is_bad, Bad);
+        let size = match kind {
+            Kind::File { .. } | Kind::Symlink { .. } | Kind::Special(_) => size,
+            _ => Size::default(),
+        };
 
-    /// Get the size of this object in bytes. `Bad` objects don't have any
-    /// reportable size, hence the `Option`.
-    pub fn size(&self) -> Option<u64> {
-        self.total_size().cloned().or_else(||
-            if self.is_file() || self.is_symlink() {
-                self.metadata().map(|md| os::size(md) )
+        Entry { size, ..Entry::blank(name, kind) }
+    }
+
+    // the directory at `path`, before anything in it has been read, for the
+    // top of a tree
+    fn unread_dir(path: &Path, name: OsString) -> Self {
+        let md = fs::metadata(path).ok();
+
+        Entry::blank(name, Kind::unread_dir(false))
+            .with_mtime(md.as_ref().map(os::mtime))
+            .with_owner(md.as_ref().map(os::owner))
+    }
+
+    /// Something that couldn't be read, and why.
+    pub fn bad(name: OsString, error: io::ErrorKind) -> Self {
+        Entry::blank(name, Kind::Bad(error))
+    }
+
+    /// Set the modification time, in seconds since the epoch.
+    pub fn with_mtime(mut self, mtime: Option<i64>) -> Self {
+        self.mtime = mtime.unwrap_or(NO_MTIME);
+        self
+    }
+
+    /// Set the user ID of the owner.
+    pub fn with_owner(mut self, owner: Option<u32>) -> Self {
+        self.owner = owner.unwrap_or(NO_OWNER);
+        self
+    }
+
+    /// Set where a symlink leads.
+    pub fn with_target(mut self, target: PathBuf) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Set how many hard links a file has, and how much of its size counts
+    /// here. Does nothing to anything but a file.
+    pub fn with_links(mut self, links: u64, share: Share) -> Self {
+        if let Kind::File { links: ref mut self_links, share: ref mut self_share } = self.kind {
+            *self_links = cmp::min(links, u32::MAX as u64) as u32;
+            *self_share = share;
+        }
+
+        self
+    }
+
+    /// Read a single directory entry (not what's in it, if it's a
+    /// directory), or `None` if `options` say it should be left out. See
+    /// `Contents::from_path` for `ancestors`.
+    pub fn read(path: &Path, options: &ScanOptions, ancestors: &[(u64, u64)]) -> Option<Self> {
+        let relative = path.strip_prefix(&options.root).unwrap_or(path);
+        let excluded = path.file_name()
+            .map(|name| options.filter.excludes(name, relative) )
+            .unwrap_or(false);
+
+        if excluded {
+            return if options.show_excluded {
+                Some(Entry::blank(entry_name(path), Kind::Excluded))
             } else {
                 None
-            }
-        )
+            };
+        }
+
+        Some(Self::read_metadata(path, options, ancestors)
+             .unwrap_or_else(|e| Entry::bad(entry_name(path), e.kind()) ))
     }
 
-    fn from_dir_entry(entry: fs::DirEntry) -> Self {
-        entry.metadata().ok().and_then(|md| {
-            if md.is_dir() {
-                Contents::from_path(entry.path()).map(|contents| {
-                    let total_size = contents.size();
+    fn read_metadata(path: &Path, options: &ScanOptions, ancestors: &[(u64, u64)]) -> io::Result<Self> {
+        let md = fs::symlink_metadata(path)?;
+        let mut inode = os::inode(&md);
+        let mut target = None;
 
-                    FSTree::Dir {
-                        contents: contents,
-                        path: entry.path().clone(),
-                        metadata: md,
-                        total_size: total_size,
-                    }
-                })
-
-            } else if md.is_file() {
-                Some(FSTree::File {
-                    path: entry.path().clone(),
-                    metadata: md,
-                })
-
-            } else if md.file_type().is_symlink() {
-                fs::read_link(entry.path()).map(|target|
-                    FSTree::Symlink {
-                        path: entry.path().clone(),
-                        metadata: md,
-                        target: target,
-                    }
-                ).ok()
+        let other_device = options.device
+            .map(|device| os::device(&md) != device )
+            .unwrap_or(false);
 
-            } else { // not sure if this can even happen, but...
-                None
+        let kind = if md.is_dir() && other_device {
+            Kind::MountPoint
+
+        } else if md.is_dir() {
+            Kind::unread_dir(false)
+
+        } else if md.is_file() {
+            Kind::File {
+                links: cmp::min(os::links(&md), u32::MAX as u64) as u32,
+                share: Share::Whole,
             }
-        }).unwrap_or(FSTree::Bad)
-    }
 
-    pub fn from_dir<P: AsRef<Path>>(path: P) -> Option<Self> {
-        let path_buf = path.as_ref().to_path_buf();
-
-        Contents::from_path(path).map(|contents| {
-            let size = contents.size();
-            FSTree::Root {
-                contents: contents,
-                path: path_buf,
-                total_size: size,
+        } else if md.file_type().is_symlink() {
+            target = Some(fs::read_link(path)?);
+
+            // we only follow links to directories, and only onto
+            // filesystems we'd have gone into anyway
+            let dir_md = if options.follow_symlinks {
+                fs::metadata(path).ok().filter(|dir_md|
+                    dir_md.is_dir() && options.device
+                        .map(|device| os::device(dir_md) == device )
+                        .unwrap_or(true)
+                )
+            } else {
+                None
+            };
+
+            match dir_md {
+                Some(ref dir_md) if ancestors.contains(&os::inode(dir_md)) =>
+                    Kind::Symlink { cycle: true },
+
+                Some(dir_md) => {
+                    inode = os::inode(&dir_md);
+                    Kind::unread_dir(true)
+                }
+
+                None => Kind::Symlink { cycle: false },
             }
+
+        } else if let Some(kind) = os::special_kind(&md) {
+            Kind::Special(kind)
+
+        } else { // not sure if this can even happen, but...
+            return Err(io::Error::other("unknown file type"));
+        };
+
+        // a directory's size is what's in it, which comes later
+        let size = match kind {
+            Kind::File { .. } | Kind::Symlink { .. } | Kind::Special(_) => Size::from_metadata(&md),
+            _ => Size::default(),
+        };
+
+        Ok(Entry {
+            name: entry_name(path),
+            size,
+            kind,
+            mtime: os::mtime(&md),
+            owner: os::owner(&md),
+            inode,
+            target,
         })
     }
 
-    pub fn list(&self) -> Option<Vec<Listing>> {
-        self.contents().map(|&Contents(ref contents)|
-            contents.iter().map(|(name, fst)|
-                (
-                    name.clone(),
-                    fst.size().unwrap_or(0),
-                    fst.is_dir(),
-
-                    if let FSTree::Symlink { ref target, .. } = *fst {
-                        Some(target.as_os_str().to_os_string())
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    /// The (device, inode) pair identifying this entry, or the directory it
+    /// leads to for a followed symlink.
+    pub fn inode(&self) -> (u64, u64) {
+        self.inode
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        self.kind.entry_kind()
+    }
+
+    /// Whether this is a directory, and not a followed symlink to one.
+    pub fn is_dir(&self) -> bool {
+        self.kind.is_dir() && !self.kind.is_linked()
+    }
+
+    pub fn is_linked(&self) -> bool {
+        self.kind.is_linked()
+    }
+
+    pub fn is_bad(&self) -> bool {
+        self.kind.entry_kind() == EntryKind::Bad
+    }
+
+    /// Whether there's anything to read inside this entry: it's a directory
+    /// or a followed symlink.
+    pub fn has_contents(&self) -> bool {
+        self.kind.is_dir()
+    }
+
+    /// Decide how much of this file's size to count, given the inodes
+    /// already counted elsewhere in the tree. Does nothing to anything that
+    /// isn't a file with more than one link.
+    pub fn share_links(&mut self, attribution: LinkAttribution, seen: &mut HashSet<(u64, u64)>) {
+        if let Kind::File { links, ref mut share } = self.kind {
+            if links > 1 {
+                *share = match attribution {
+                    LinkAttribution::Split => Share::Split(links as u64),
+
+                    LinkAttribution::First => if seen.insert(self.inode) {
+                        Share::Whole
                     } else {
-                        None
+                        Share::Duplicate
                     },
-                )
-            ).collect()
-        )
+                };
+            }
+        }
+    }
+}
+
+// the name `path` would be listed under, which for the root of a filesystem
+// is the whole thing
+fn entry_name(path: &Path) -> OsString {
+    path.file_name().unwrap_or(path.as_os_str()).to_os_string()
+}
+
+/// A single level of a directory, sorted by name.
+#[derive(Default)]
+pub struct Contents(Vec<Entry>);
+
+impl Contents {
+
+    pub fn new() -> Self {
+        Contents(Vec::new())
+    }
+
+    /// A directory level made up of `entries`, in any order.
+    pub fn from_entries(entries: Vec<Entry>) -> Self {
+        let mut entries = entries;
+        entries.sort_by(|a, b| a.name.cmp(&b.name) );
+        Contents(entries)
     }
 
-    pub fn entry(&self, name: &OsString) -> Option<&FSTree> {
-        self.contents().and_then(|n_contents| {
-            let contents = n_contents.get_map();
-            contents.get(name)
+    /// Read a single level of a directory. Subdirectories come back empty;
+    /// filling them in is the `Scanner`'s job.
+    ///
+    /// `ancestors` are the (device, inode) pairs of the directories this one
+    /// is inside (including itself), for spotting symlinks that lead back
+    /// into them. It only matters when following symlinks.
+    pub fn from_path<P: AsRef<Path>>(
+        path: P,
+        options: &ScanOptions,
+        ancestors: &[(u64, u64)],
+    ) -> io::Result<Contents> {
+        let path = path.as_ref();
+        let mut unreadable = 0;
+
+        fs::read_dir(path).map(|r_entries| { // map over the directory entries
+            let entries: Vec<Entry> = r_entries.filter_map(|r_entry| match r_entry { // they are in Results
+                Ok(entry) => Entry::read(&entry.path(), options, ancestors),
+
+                // we don't even get a name for these, so make one up
+                Err(e) => {
+                    unreadable += 1;
+                    let name = OsString::from(format!("<unreadable entry {}>", unreadable));
+                    Some(Entry::bad(name, e.kind()))
+                }
+            }).collect();
+
+            // the tree wants them in order, and this is the place to do it
+            // since there's a thread for it
+            Contents::from_entries(entries)
         })
     }
 
-    pub fn entries(&self, names: &[OsString]) -> Option<&FSTree> {
-        let mut fst = Some(self);
+    pub fn size(&self) -> Size {
+        self.0.iter()
+           .map(|entry| entry.kind.counted_size(entry.size) )
+           .sum()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Entry> {
+        self.0.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Entry> {
+        self.0.iter_mut()
+    }
+
+    /// The number of `Bad` entries in here.
+    pub fn error_count(&self) -> usize {
+        self.0.iter().filter(|entry| entry.is_bad() ).count()
+    }
+
+    /// The directories directly inside this one that still need reading,
+    /// including followed symlinks.
+    pub fn subdirs(&self) -> Vec<&Entry> {
+        self.0.iter().filter(|entry| entry.has_contents() ).collect()
+    }
+}
+
+/// Marks an empty spot in `Names::table`, and the name of a freed slot.
+const NO_NAME: u32 = u32::MAX;
+
+// every distinct name in a tree, stored once, since most trees have the same
+// few (`.git`, `Makefile`, `index.js`...) over and over. They're all run
+// together in `bytes`, and `table` is a hash table of their ids for finding
+// the ones we already have. `refs` counts the entries using each one, and
+// `garbage` is how many bytes belong to names nothing uses any more, which
+// get thrown out once there are enough of them (see `FSTree::compact_names`)
+#[derive(Default)]
+struct Names {
+    bytes: Vec<u8>,
+    ends: Vec<usize>,
+    table: Vec<u32>,
+    refs: Vec<u32>,
+    garbage: usize,
+}
+
+impl Names {
+
+    fn get(&self, id: u32) -> &OsStr {
+        // every name went in whole, straight out of an `OsStr`
+        unsafe { OsStr::from_encoded_bytes_unchecked(self.get_bytes(id)) }
+    }
+
+    fn get_bytes(&self, id: u32) -> &[u8] {
+        let id = id as usize;
+        let start = if id == 0 { 0 } else { self.ends[id - 1] };
+        &self.bytes[start..self.ends[id]]
+    }
+
+    // one less entry uses the name `id`
+    fn release(&mut self, id: u32) {
+        let refs = &mut self.refs[id as usize];
+        *refs -= 1;
+
+        if *refs == 0 {
+            self.garbage += self.get_bytes(id).len();
+        }
+    }
+
+    // whether enough of `bytes` is garbage to be worth getting rid of
+    fn is_wasteful(&self) -> bool {
+        self.garbage > 4096 && self.garbage * 2 > self.bytes.len()
+    }
+
+    fn intern(&mut self, name: &OsStr) -> u32 {
+        // keep the table no more than three quarters full
+        if (self.ends.len() + 1) * 4 > self.table.len() * 3 {
+            self.grow();
+        }
+
+        let mask = self.table.len() - 1;
+        let mut spot = hash_name(name) & mask;
+
+        loop {
+            match self.table[spot] {
+                NO_NAME => {
+                    let id = self.ends.len() as u32;
+                    self.bytes.extend_from_slice(name.as_encoded_bytes());
+                    self.ends.push(self.bytes.len());
+                    self.refs.push(1);
+                    self.table[spot] = id;
+                    return id;
+                }
+
+                id if self.get(id) == name => {
+                    // it might have been on its way out
+                    if self.refs[id as usize] == 0 {
+                        self.garbage -= name.len();
+                    }
+
+                    self.refs[id as usize] += 1;
+                    return id;
+                }
+
+                _ => spot = (spot + 1) & mask,
+            }
+        }
+    }
+
+    fn grow(&mut self) {
+        let len = cmp::max(64, self.table.len() * 2);
+        let mut table = vec![NO_NAME; len];
 
-        for name in names {
-            let cur = fst.take();
-            fst = cur.map(|fst| fst.entry(name) )
-                .unwrap_or_else(|| return None )
+        for id in 0..self.ends.len() as u32 {
+            let mut spot = hash_name(self.get(id)) & (len - 1);
+            while table[spot] != NO_NAME {
+                spot = (spot + 1) & (len - 1);
+            }
+            table[spot] = id;
         }
 
+        self.table = table;
+    }
+}
+
+fn hash_name(name: &OsStr) -> usize {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+// an entry in the tree. `size` is the total of everything inside, for
+// directories
+struct Slot {
+    name: u32,
+    parent: Id,
+    size: Size,
+    mtime: i64,
+    kind: Kind,
+}
+
+/// A directory tree, or as much of it as has been read so far.
+///
+/// The entries all live side by side in one `Vec` and point at each other
+/// by index. Each distinct name is stored once however many times it turns
+/// up, and paths are put back together from the names on the way down
+/// whenever one is asked for. Only the handful of metadata fields we
+/// actually show are kept. That's what lets a scan of tens of millions of
+/// files fit in memory.
+///
+/// Entries are found by `names`, the names leading from the top of the tree
+/// down to them, and looked at through `Node`s.
+pub struct FSTree {
+    path: PathBuf,
+    slots: Vec<Slot>,
+
+    // slots left behind by removed entries, for reuse
+    free: Vec<Id>,
+
+    names: Names,
+
+    // where symlinks lead. Few enough entries have one that it isn't worth
+    // a field in every slot
+    targets: HashMap<Id, PathBuf>,
+
+    // who owns what. Trees tend to belong almost entirely to one user, so
+    // only the entries that don't belong to the same one as the top are
+    // listed
+    owner: u32,
+    owners: HashMap<Id, u32>,
+
+    // the (device, inode) of every file with more than one link, so their
+    // sizes can be shared out again when part of the tree is read again,
+    // and the other way round, for finding the rest of a file's links
+    inodes: HashMap<Id, (u64, u64)>,
+    links: HashMap<(u64, u64), Vec<Id>>,
+}
+
+impl FSTree {
+
+    /// An empty tree for the directory at `path`, which has yet to be read.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let entry = Entry::unread_dir(&path, entry_name(&path));
+
+        Self::from_entry(path, entry)
+    }
+
+    /// An empty tree for several directories at once, under a top level
+    /// that isn't anywhere on disk. The top level has no path, and each
+    /// directory is named after its whole path, so everything's path comes
+    /// out the same as if the directories had been scanned one at a time.
+    /// The directories have yet to be read.
+    pub fn top_level(paths: &[PathBuf]) -> Self {
+        // the top level belongs to nobody, but going by the first directory
+        // keeps the owners of everything else from having to be listed
+        let owner = paths.first()
+            .and_then(|path| fs::metadata(path).ok() )
+            .map(|md| os::owner(&md) );
+        let top = Entry::blank(OsString::new(), Kind::unread_dir(false)).with_owner(owner);
+
+        let mut fst = Self::from_entry(PathBuf::new(), top);
+        let dirs = paths.iter()
+            .map(|path| Entry::unread_dir(path, path.as_os_str().to_os_string()) )
+            .collect();
+
+        fst.graft(&[], Ok(Contents::from_entries(dirs)));
         fst
     }
 
-    pub fn entry_mut(&mut self, name: &OsString) -> Option<&mut FSTree> {
-        self.contents_mut().and_then(|n_contents| {
-            let contents = n_contents.get_map_mut();
-            contents.get_mut(name)
-        })
+    /// A tree with nothing in it but `entry`, which lives at `path`.
+    pub fn from_entry(path: PathBuf, entry: Entry) -> Self {
+        let mut fst = FSTree {
+            path,
+            slots: Vec::new(),
+            free: Vec::new(),
+            names: Names::default(),
+            targets: HashMap::new(),
+            owner: entry.owner,
+            owners: HashMap::new(),
+            inodes: HashMap::new(),
+            links: HashMap::new(),
+        };
+
+        fst.add(NO_PARENT, entry);
+        fst
+    }
+
+    pub fn from_dir<P: AsRef<Path>>(path: P) -> Option<Self> {
+        Scanner::new().scan(path)
+    }
+
+    /// Where the top of the tree is on disk, which is nowhere (an empty
+    /// path) for a top level.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether this tree was made by `top_level`.
+    pub fn is_top_level(&self) -> bool {
+        self.path.as_os_str().is_empty()
+    }
+
+    /// The directory that was scanned to find the entry at `names`: the top
+    /// of the tree, or whichever directory it's in for a top level.
+    pub fn scan_root(&self, names: &[OsString]) -> PathBuf {
+        match names.first() {
+            Some(name) if self.is_top_level() => PathBuf::from(name),
+            _ => self.path.clone(),
+        }
+    }
+
+    pub fn root(&self) -> Node<'_> {
+        Node { tree: self, id: TOP }
+    }
+
+    pub fn entry(&self, name: &OsString) -> Option<Node<'_>> {
+        self.root().entry(name)
     }
 
-    pub fn is_empty(&self) -> Option<bool> {
-        self.contents().map(|n_contents|
-            n_contents.get_map().is_empty()
-        )
+    pub fn entries(&self, names: &[OsString]) -> Option<Node<'_>> {
+        self.root().entries(names)
     }
 
-    pub fn delete_path(&mut self, names: &[OsString]) -> Option<u64> {
-        if names.is_empty() {
-            panic!("cannot delete empty path");
+    /// Fill in the (so far empty) directory at `names` with `contents`,
+    /// adding their size to it and to every directory above it (up to the
+    /// first followed symlink). An error means the directory turned out to
+    /// be unreadable, and it's replaced with `Bad`. Returns `false` if
+    /// there's no directory there any more, e.g. because it was deleted in
+    /// the meantime.
+    pub fn graft(&mut self, names: &[OsString], contents: Result<Contents, io::ErrorKind>) -> bool {
+        let id = match self.find(names) {
+            Some(id) if self.slot(id).kind.is_dir() => id,
+            _ => return false,
+        };
 
+        match contents {
+            Ok(contents) => {
+                // this directory is done, but the ones inside it are just
+                // starting
+                let after = (
+                    contents.size(),
+                    contents.subdirs().len() as u32,
+                    contents.error_count() as u32,
+                );
+
+                let mut children = self.take_children(id).into_vec();
+                let sorted = children.is_empty();
+
+                for entry in contents.0 {
+                    children.push(self.add(id, entry));
+                }
+
+                if !sorted {
+                    children.sort_by(|&a, &b| self.name(a).cmp(self.name(b)) );
+                }
+
+                self.put_children(id, children.into_boxed_slice());
+                self.retally(id, (Size::default(), 1, 0), after);
+            }
+
+            Err(error) => {
+                let before = self.tally(id);
+
+                for child in self.take_children(id).iter() {
+                    self.release(*child);
+                }
+
+                let slot = self.slot_mut(id);
+                slot.kind = Kind::Bad(error);
+                slot.size = Size::default();
+
+                let parent = slot.parent;
+                if parent != NO_PARENT {
+                    let after = self.tally(id);
+                    self.retally(parent, before, after);
+                }
+
+                self.compact_names();
+            }
+        }
+
+        true
+    }
+
+    /// Replace everything in this tree with what's in `scanned`, a freshly
+    /// scanned tree of the same directory. The top of this tree stays what
+    /// it was (a followed symlink, say). Does nothing if that isn't a
+    /// directory.
+    pub fn fill(&mut self, scanned: FSTree) {
+        if let Kind::Dir { linked, .. } = self.slot(TOP).kind {
+            let target = self.targets.remove(&TOP);
+            let path = std::mem::replace(&mut self.path, PathBuf::new());
+
+            *self = scanned;
+            self.path = path;
+
+            if let Kind::Dir { linked: ref mut top_linked, .. } = self.slot_mut(TOP).kind {
+                *top_linked = linked;
+            }
+
+            if let Some(target) = target {
+                self.targets.insert(TOP, target);
+            }
+        }
+    }
+
+    /// Put the whole of `fst` into the tree at `names`, replacing whatever
+    /// was there. Returns `false` if the directory it should go in isn't
+    /// there.
+    pub fn insert_entry(&mut self, names: &[OsString], fst: FSTree) -> bool {
+        let mut fst = fst;
+
+        let (name, dir) = match names.split_last() {
+            Some(split) => split,
+            None => return false,
+        };
+
+        let parent = match self.find(dir) {
+            Some(id) if self.slot(id).kind.is_dir() => id,
+            _ => return false,
+        };
+
+        let old = self.child(parent, name);
+
+        // a file that's changed is still the same link as far as counting
+        // hard links goes
+        if let Some(Kind::File { share, .. }) = old.map(|old| &self.slot(old).kind ) {
+            if let Kind::File { share: ref mut new_share, .. } = fst.slot_mut(TOP).kind {
+                *new_share = *share;
+            }
+        }
+
+        let id = self.copy(&fst, TOP, parent);
+        let copied_name = self.slot(id).name;
+        self.slot_mut(id).name = self.names.intern(name);
+        self.names.release(copied_name);
+
+        let before = match old {
+            // it takes the old one's place, which is where it goes by name
+            Some(old) => {
+                let before = self.tally(old);
+                self.replace_child(parent, old, id);
+                self.release(old);
+                before
+            }
+
+            None => {
+                self.link(parent, id);
+                Default::default()
+            }
+        };
+
+        let after = self.tally(id);
+        self.retally(parent, before, after);
+
+        self.reshare(id);
+        self.compact_names();
+        true
+    }
+
+    /// Take the entry at `names` out of the tree (without touching the
+    /// disk), fixing up the totals above it. Returns `false` if it wasn't
+    /// there.
+    pub fn remove_entry(&mut self, names: &[OsString]) -> bool {
+        let id = match self.find(names) {
+            Some(id) if id != TOP => id,
+            _ => return false,
+        };
+
+        let parent = self.slot(id).parent;
+        let before = self.tally(id);
+
+        self.unlink(parent, id);
+        self.release(id);
+        self.retally(parent, before, Default::default());
+        self.compact_names();
+        true
+    }
+
+    /// Delete the entry at `names` from the disk, and then from the tree.
+    /// The directories a top level is made of can't be deleted, any more
+    /// than the top of any other tree can.
+    pub fn delete_path(&mut self, names: &[OsString]) -> io::Result<()> {
+        let id = match self.find(names) {
+            Some(id) if id != TOP && !(self.is_top_level() && names.len() == 1) => id,
+            _ => return Err(io::Error::new(io::ErrorKind::NotFound, "no such entry")),
+        };
+
+        let path = self.path_of(id);
+
+        match self.slot(id).kind {
+            Kind::Dir { linked: false, .. } => fs::remove_dir_all(&path),
+            Kind::Dir { linked: true, .. } => fs::remove_file(&path), // just the link
+            Kind::File { .. } | Kind::Symlink { .. } | Kind::Special(_) => fs::remove_file(&path),
+            _ => Err(io::Error::other("Could not delete this entry")),
+        }?;
+
+        self.remove_entry(names);
+        Ok(())
+    }
+
+    /// Give back any memory set aside for entries that never came, which
+    /// is worth doing once a scan is over.
+    pub fn shrink_to_fit(&mut self) {
+        self.slots.shrink_to_fit();
+        self.free.shrink_to_fit();
+        self.names.bytes.shrink_to_fit();
+        self.names.ends.shrink_to_fit();
+        self.names.refs.shrink_to_fit();
+    }
+
+    fn slot(&self, id: Id) -> &Slot {
+        &self.slots[id as usize]
+    }
+
+    fn slot_mut(&mut self, id: Id) -> &mut Slot {
+        &mut self.slots[id as usize]
+    }
+
+    fn name(&self, id: Id) -> &OsStr {
+        self.names.get(self.slot(id).name)
+    }
+
+    fn find(&self, names: &[OsString]) -> Option<Id> {
+        names.iter().try_fold(TOP, |id, name| self.child(id, name) )
+    }
+
+    fn child(&self, id: Id, name: &OsStr) -> Option<Id> {
+        let children = self.slot(id).kind.children();
+
+        children.binary_search_by(|&child| self.name(child).cmp(name) )
+            .ok()
+            .map(|i| children[i] )
+    }
+
+    fn owner_of(&self, id: Id) -> u32 {
+        self.owners.get(&id).cloned().unwrap_or(self.owner)
+    }
+
+    fn path_of(&self, id: Id) -> PathBuf {
+        let mut names = Vec::new();
+        let mut id = id;
+
+        while self.slot(id).parent != NO_PARENT {
+            names.push(self.name(id));
+            id = self.slot(id).parent;
+        }
+
+        let mut path = self.path.clone();
+        for name in names.iter().rev() {
+            path.push(name);
+        }
+
+        path
+    }
+
+    // whether `id` is `dir` or somewhere inside it
+    fn is_inside(&self, id: Id, dir: Id) -> bool {
+        let mut id = id;
+
+        while id != NO_PARENT {
+            if id == dir {
+                return true;
+            }
+
+            id = self.slot(id).parent;
+        }
+
+        false
+    }
+
+    // work out again how much of each multiply-linked file in `id` counts,
+    // now that it's been read again by itself. Whatever read it didn't know
+    // which links the rest of the tree already counts, so anything counted
+    // elsewhere is a duplicate in here. Split shares don't depend on the
+    // other links, so they're left as they are.
+    fn reshare(&mut self, id: Id) {
+        let mut inside = Vec::new();
+        self.linked_files(id, &mut inside);
+
+        // the same order they were put in, as near as it matters
+        inside.sort_unstable();
+
+        let mut counted = HashSet::new();
+        for &file in &inside {
+            let inode = self.inodes[&file];
+            let elsewhere = self.links[&inode].iter().any(|&other|
+                !self.is_inside(other, id)
+                    && matches!(self.slot(other).kind, Kind::File { share: Share::Whole, .. })
+            );
+
+            if elsewhere {
+                counted.insert(inode);
+            }
+        }
+
+        for file in inside {
+            let before = self.tally(file);
+            let inode = self.inodes[&file];
+
+            if let Kind::File { ref mut share, .. } = self.slot_mut(file).kind {
+                *share = match *share {
+                    Share::Split(links) => Share::Split(links),
+                    _ if counted.insert(inode) => Share::Whole,
+                    _ => Share::Duplicate,
+                };
+            }
+
+            let after = self.tally(file);
+            let parent = self.slot(file).parent;
+            self.retally(parent, before, after);
+        }
+    }
+
+    // the files with more than one link in `id`, or `id` itself if it's one
+    fn linked_files(&self, id: Id, files: &mut Vec<Id>) {
+        if self.inodes.contains_key(&id) {
+            files.push(id);
+        }
+
+        for &child in self.slot(id).kind.children() {
+            self.linked_files(child, files);
+        }
+    }
+
+    fn tally(&self, id: Id) -> Tally {
+        let slot = self.slot(id);
+        (slot.kind.counted_size(slot.size), slot.kind.pending(), slot.kind.error_count())
+    }
+
+    // account for something in the directory `id` going from contributing
+    // `before` to contributing `after`, there and all the way up (though the
+    // size stops at the first followed symlink)
+    fn retally(&mut self, id: Id, before: Tally, after: Tally) {
+        let mut id = id;
+        let mut counted = true;
+
+        while id != NO_PARENT {
+            let slot = self.slot_mut(id);
+
+            if counted {
+                slot.size = slot.size - before.0 + after.0;
+            }
+
+            if let Kind::Dir { ref mut pending, ref mut errors, linked, .. } = slot.kind {
+                *pending = *pending - before.1 + after.1;
+                *errors = *errors - before.2 + after.2;
+                counted = counted && !linked;
+            }
+
+            id = slot.parent;
+        }
+    }
+
+    // make a slot for `entry` in `parent`, which is left to the caller to
+    // put in the parent's children
+    fn add(&mut self, parent: Id, entry: Entry) -> Id {
+        let slot = Slot {
+            name: self.names.intern(&entry.name),
+            parent,
+            size: entry.size,
+            mtime: entry.mtime,
+            kind: entry.kind,
+        };
+
+        self.alloc(slot, entry.target, entry.owner, entry.inode)
+    }
+
+    fn alloc(&mut self, slot: Slot, target: Option<PathBuf>, owner: u32, inode: (u64, u64)) -> Id {
+        let id = match self.free.pop() {
+            Some(id) => {
+                *self.slot_mut(id) = slot;
+                id
+            }
+
+            None => {
+                self.slots.push(slot);
+                (self.slots.len() - 1) as Id
+            }
+        };
+
+        if let Some(target) = target {
+            self.targets.insert(id, target);
+        }
+
+        if owner != self.owner {
+            self.owners.insert(id, owner);
+        }
+
+        if let Kind::File { links, .. } = self.slot(id).kind {
+            if links > 1 {
+                self.inodes.insert(id, inode);
+                self.links.entry(inode).or_default().push(id);
+            }
+        }
+
+        id
+    }
+
+    // copy `id` from `from`, and everything in it, into a slot in `parent`
+    // (again, not yet among its children)
+    fn copy(&mut self, from: &FSTree, id: Id, parent: Id) -> Id {
+        let source = from.slot(id);
+
+        let slot = Slot {
+            name: self.names.intern(from.name(id)),
+            parent,
+            size: source.size,
+            mtime: source.mtime,
+            kind: source.kind.clone(),
+        };
+
+        let inode = from.inodes.get(&id).cloned().unwrap_or_default();
+        let copy = self.alloc(slot, from.targets.get(&id).cloned(), from.owner_of(id), inode);
+
+        // the children were cloned along with the rest, but they're still
+        // `from`'s ids
+        let mut children = self.take_children(copy);
+        for child in children.iter_mut() {
+            *child = self.copy(from, *child, copy);
+        }
+        self.put_children(copy, children);
+
+        copy
+    }
+
+    // free up the slots of `id` and everything in it
+    fn release(&mut self, id: Id) {
+        for child in self.take_children(id).iter() {
+            self.release(*child);
+        }
+
+        self.targets.remove(&id);
+        self.owners.remove(&id);
+
+        if let Some(inode) = self.inodes.remove(&id) {
+            if let Some(ids) = self.links.get_mut(&inode) {
+                ids.retain(|&other| other != id );
+                if ids.is_empty() {
+                    self.links.remove(&inode);
+                }
+            }
+        }
+        let slot = self.slot_mut(id);
+        let name = std::mem::replace(&mut slot.name, NO_NAME);
+        slot.kind = Kind::Excluded;
+        self.names.release(name);
+        self.free.push(id);
+    }
+
+    // start the names over with just the ones still in use, if enough have
+    // gone out of use, since entries come and go for as long as the tree is
+    // being watched
+    fn compact_names(&mut self) {
+        if !self.names.is_wasteful() {
+            return;
+        }
+
+        let mut names = Names::default();
+        for slot in self.slots.iter_mut().filter(|slot| slot.name != NO_NAME ) {
+            slot.name = names.intern(self.names.get(slot.name));
+        }
+
+        self.names = names;
+    }
+
+    fn take_children(&mut self, id: Id) -> Box<[Id]> {
+        if let Kind::Dir { ref mut children, .. } = self.slot_mut(id).kind {
+            std::mem::take(children)
         } else {
-            let name = names.first().unwrap();
-            let others = &names[1..];
-
-            if names.len() == 1 { // delete from this level
-                let o_deleted_size = self.contents()
-                    .map(|cs| cs.get_map() )
-                    .and_then(|map| {map.get(name)})
-                    .and_then(|fst| {
-
-                    let fst_size = fst.size();
-                    fst.delete().ok().and_then(|_| fst_size )
-                });
-
-                o_deleted_size.map(|size| {
-                    *self.total_size_mut().unwrap() -= size;
-
-                    self.contents_mut().map(|cs| cs.get_map_mut() ).and_then(|map| {
-                        map.remove(name)
-                    });
-
-                    *self.total_size().unwrap()
-                })
-
-            } else { // go deeper to delete
-                let mut cur_size = None;
-
-                // recursive call to delete
-                let new_size = self.entry_mut(name) .and_then(|fst| {
-                    cur_size = fst.size();
-                    fst.delete_path(others)
-                });
-
-                // now that we have the new size, update this node and pass
-                // *its* new size on down the line
-                new_size.map(|new_size| {
-                    let size_delta = cur_size.unwrap() - new_size;
-                    *self.total_size_mut().unwrap() -= size_delta;
-                    *self.total_size().unwrap()
-                })
+            Box::new([])
+        }
+    }
+
+    fn put_children(&mut self, id: Id, new_children: Box<[Id]>) {
+        if let Kind::Dir { ref mut children, .. } = self.slot_mut(id).kind {
+            *children = new_children;
+        }
+    }
+
+    fn link(&mut self, parent: Id, id: Id) {
+        let mut children = self.take_children(parent).into_vec();
+        let at = children.binary_search_by(|&child| self.name(child).cmp(self.name(id)) )
+            .unwrap_or_else(|at| at );
+        children.insert(at, id);
+        self.put_children(parent, children.into_boxed_slice());
+    }
+
+    // put `new` where `old` is among `parent`'s children, which is only
+    // right if they have the same name
+    fn replace_child(&mut self, parent: Id, old: Id, new: Id) {
+        if let Kind::Dir { ref mut children, .. } = self.slot_mut(parent).kind {
+            if let Some(child) = children.iter_mut().find(|child| **child == old ) {
+                *child = new;
             }
         }
     }
 
-    fn delete(&self) -> std::io::Result<()> {
-        match *self {
-            FSTree::Dir { ref path, .. } => fs::remove_dir_all(path),
-            FSTree::File { ref path, .. } => fs::remove_file(path),
-            FSTree::Symlink { ref path, .. } => fs::remove_file(path),
-            _ => Err(
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Could not delete this entry"
-                )
-            ),
+    fn unlink(&mut self, parent: Id, id: Id) {
+        let mut children = self.take_children(parent).into_vec();
+        children.retain(|&child| child != id );
+        self.put_children(parent, children.into_boxed_slice());
+    }
+}
+
+/// A look at one entry in an `FSTree`.
+#[derive(Clone, Copy)]
+pub struct Node<'t> {
+    tree: &'t FSTree,
+    id: Id,
+}
+
+impl<'t> Node<'t> {
+
+    fn slot(&self) -> &'t Slot {
+        self.tree.slot(self.id)
+    }
+
+    pub fn name(&self) -> &'t OsStr {
+        self.tree.name(self.id)
+    }
+
+    /// Where this entry is on disk, which is put together from the names on
+    /// the way down to it.
+    pub fn path(&self) -> PathBuf {
+        self.tree.path_of(self.id)
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        self.slot().kind.entry_kind()
+    }
+
+    pub fn is_linked(&self) -> bool {
+        self.slot().kind.is_linked()
+    }
+
+    pub fn is_bad(&self) -> bool {
+        self.kind() == EntryKind::Bad
+    }
+
+    pub fn is_excluded(&self) -> bool {
+        self.kind() == EntryKind::Excluded
+    }
+
+    /// Whether this entry has contents: it's a directory or a followed
+    /// symlink.
+    pub fn has_contents(&self) -> bool {
+        self.slot().kind.is_dir()
+    }
+
+    /// Get the size of this entry in bytes. `Bad` entries don't have any
+    /// reportable size, hence the `Option`.
+    pub fn size(&self) -> Option<Size> {
+        let slot = self.slot();
+        slot.kind.shown_size(slot.size)
+    }
+
+    /// How much of this entry's size counts towards the directories above
+    /// it, which is none of it for a followed symlink.
+    pub fn counted_size(&self) -> Size {
+        let slot = self.slot();
+        slot.kind.counted_size(slot.size)
+    }
+
+    /// The whole size of this entry, before any of it is shared out between
+    /// hard links.
+    pub fn whole_size(&self) -> Option<Size> {
+        let slot = self.slot();
+        slot.kind.shown_size(slot.size).map(|_| slot.size )
+    }
+
+    /// How much of this file's size counts here, if it's a file.
+    pub fn share(&self) -> Option<Share> {
+        if let Kind::File { share, .. } = self.slot().kind { Some(share) } else { None }
+    }
+
+    /// When this entry was last modified, in seconds since the epoch.
+    pub fn mtime(&self) -> Option<i64> {
+        Some(self.slot().mtime).filter(|&mtime| mtime != NO_MTIME )
+    }
+
+    /// The user ID of whoever owns this entry, if we know.
+    pub fn owner(&self) -> Option<u32> {
+        Some(self.tree.owner_of(self.id)).filter(|&owner| owner != NO_OWNER )
+    }
+
+    /// Where this entry leads, if it's a symlink (followed or not).
+    pub fn target(&self) -> Option<&'t Path> {
+        self.tree.targets.get(&self.id).map(|target| target.as_path() )
+    }
+
+    /// What went wrong, if this entry couldn't be read.
+    pub fn error(&self) -> Option<io::ErrorKind> {
+        if let Kind::Bad(error) = self.slot().kind { Some(error) } else { None }
+    }
+
+    /// The number of hard links to this entry, if it's a file.
+    pub fn links(&self) -> Option<u64> {
+        if let Kind::File { links, .. } = self.slot().kind { Some(links as u64) } else { None }
+    }
+
+    /// Whether the scanner still has work to do anywhere inside this entry.
+    pub fn is_scanning(&self) -> bool {
+        self.slot().kind.pending() > 0
+    }
+
+    /// The number of `Bad` entries in this one, including itself.
+    pub fn error_count(&self) -> usize {
+        self.slot().kind.error_count() as usize
+    }
+
+    /// Every `Bad` entry in here, with what went wrong.
+    pub fn errors_list(&self) -> Vec<(PathBuf, io::ErrorKind)> {
+        let mut errors = Vec::new();
+        self.collect_errors(&mut errors);
+        errors
+    }
+
+    fn collect_errors(&self, errors: &mut Vec<(PathBuf, io::ErrorKind)>) {
+        match self.error() {
+            Some(error) => errors.push((self.path(), error)),
+
+            // no need to look inside directories with nothing wrong in them
+            None => if self.error_count() > 0 {
+                for node in self.children() {
+                    node.collect_errors(errors);
+                }
+            },
+        }
+    }
+
+    /// Everything directly inside this entry, in order of name. There's
+    /// nothing for anything without contents.
+    pub fn children(&self) -> impl Iterator<Item = Node<'t>> + 't {
+        let tree = self.tree;
+        self.slot().kind.children().iter().map(move |&id| Node { tree, id } )
+    }
+
+    pub fn entry(&self, name: &OsStr) -> Option<Node<'t>> {
+        self.tree.child(self.id, name).map(|id| Node { tree: self.tree, id } )
+    }
+
+    pub fn entries(&self, names: &[OsString]) -> Option<Node<'t>> {
+        names.iter()
+            .try_fold(self.id, |id, name| self.tree.child(id, name) )
+            .map(|id| Node { tree: self.tree, id } )
+    }
+
+    pub fn list(&self) -> Option<Vec<Listing>> {
+        if !self.has_contents() {
+            return None;
+        }
+
+        Some(self.children().map(|node|
+            Listing {
+                name: node.name().to_os_string(),
+                size: node.size().unwrap_or_default(),
+                kind: node.kind(),
+                is_dir: node.has_contents(),
+                symlink_target: node.target().map(|target| target.as_os_str().to_os_string() ),
+
+                scanning: node.is_scanning(),
+                links: node.links().unwrap_or(1),
+                error: node.error(),
+                change: None,
+            }
+        ).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(n: u64) -> Size {
+        Size { disk: n, apparent: n }
+    }
+
+    fn names(names: &[&str]) -> Vec<OsString> {
+        names.iter().map(OsString::from).collect()
+    }
+
+    // /data, holding a file and a directory `dir` with two files in it
+    fn tree() -> FSTree {
+        let top = Entry::new(OsString::from("data"), EntryKind::Dir, Size::default());
+        let mut fst = FSTree::from_entry(PathBuf::from("/data"), top);
+
+        fst.graft(&[], Ok(Contents::from_entries(vec![
+            Entry::new(OsString::from("file"), EntryKind::File, size(10)),
+            Entry::new(OsString::from("dir"), EntryKind::Dir, Size::default()),
+        ])));
+
+        fst.graft(&names(&["dir"]), Ok(Contents::from_entries(vec![
+            Entry::new(OsString::from("a"), EntryKind::File, size(100)),
+            Entry::new(OsString::from("b"), EntryKind::File, size(1000)),
+        ])));
+
+        fst
+    }
+
+    fn file(n: u64) -> FSTree {
+        let entry = Entry::new(OsString::from("anything"), EntryKind::File, size(n));
+        FSTree::from_entry(PathBuf::from("/elsewhere/anything"), entry)
+    }
+
+    // every directory adds up to what's in it, and every path is its
+    // directory's path and its name
+    fn check(node: Node) {
+        if node.has_contents() {
+            let total = node.children().map(|child| child.counted_size() ).sum();
+            assert_eq!(node.size(), Some(total), "{:?}", node.path());
         }
+
+        for child in node.children() {
+            assert_eq!(child.path(), node.path().join(child.name()));
+            check(child);
+        }
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let mut fst = tree();
+        check(fst.root());
+        assert_eq!(fst.root().size(), Some(size(1110)));
+
+        // somewhere new
+        assert!(fst.insert_entry(&names(&["dir", "c"]), file(5)));
+        let c = fst.entries(&names(&["dir", "c"])).unwrap();
+        assert_eq!(c.path(), Path::new("/data/dir/c"));
+        assert_eq!(fst.root().size(), Some(size(1115)));
+        check(fst.root());
+
+        // in place of something that's there already
+        assert!(fst.insert_entry(&names(&["dir", "a"]), file(1)));
+        assert_eq!(fst.entries(&names(&["dir"])).unwrap().size(), Some(size(1006)));
+        let order: Vec<&OsStr> = fst.entries(&names(&["dir"])).unwrap().children().map(|child| child.name() ).collect();
+        assert_eq!(order, ["a", "b", "c"]);
+        check(fst.root());
+
+        assert!(fst.remove_entry(&names(&["dir"])));
+        assert!(fst.entries(&names(&["dir", "a"])).is_none());
+        assert_eq!(fst.root().size(), Some(size(10)));
+        check(fst.root());
+
+        assert!(!fst.remove_entry(&names(&["dir"])));
+        assert!(!fst.insert_entry(&names(&["dir", "a"]), file(1)));
+    }
+
+    #[test]
+    fn slots_are_reused() {
+        let mut fst = tree();
+        let mut slots = None;
+
+        for _ in 0..10 {
+            assert!(fst.remove_entry(&names(&["dir"])));
+            assert!(fst.insert_entry(&names(&["dir"]), tree()));
+            assert!(fst.insert_entry(&names(&["file"]), file(10)));
+
+            // the first time round needs a few more, but after that the
+            // ones given back are enough
+            assert_eq!(*slots.get_or_insert(fst.slots.len()), fst.slots.len());
+        }
+
+        assert_eq!(fst.entries(&names(&["dir", "dir", "b"])).unwrap().path(), Path::new("/data/dir/dir/b"));
+        check(fst.root());
+    }
+
+    #[test]
+    fn names_are_freed() {
+        let mut fst = tree();
+
+        // like a log file that's rotated over and over under a watch
+        for i in 0..10_000 {
+            let path = vec![OsString::from("dir"), OsString::from(format!("rotated-log-file.{}", i))];
+
+            assert!(fst.insert_entry(&path, file(1)));
+            assert!(fst.remove_entry(&path));
+        }
+
+        assert!(fst.names.bytes.len() < 10_000, "{}", fst.names.bytes.len());
+        assert_eq!(fst.entries(&names(&["dir", "b"])).unwrap().path(), Path::new("/data/dir/b"));
+        assert_eq!(fst.root().size(), Some(size(1110)));
+        check(fst.root());
+
+        // names that are still in use stay put however often they're used
+        for _ in 0..1000 {
+            assert!(fst.insert_entry(&names(&["dir", "a"]), file(100)));
+        }
+
+        assert!(fst.names.bytes.len() < 10_000, "{}", fst.names.bytes.len());
+        check(fst.root());
     }
 }
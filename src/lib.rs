@@ -0,0 +1,44 @@
+// Copyright (C) 2016  Michael Dunsmuir
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+pub extern crate rustbox;
+extern crate unicode_width;
+#[cfg(target_os = "linux")]
+extern crate inotify;
+#[cfg(test)]
+extern crate tempdir;
+
+pub mod diff;
+pub mod fstree;
+pub mod ncdu;
+pub mod os;
+pub mod pattern;
+pub mod report;
+pub mod rules;
+pub mod scan;
+pub mod snapshot;
+pub mod top;
+pub mod ui;
+pub mod watch;
+
+pub use diff::*;
+pub use fstree::*;
+pub use pattern::*;
+pub use report::*;
+pub use rules::*;
+pub use scan::*;
+pub use top::*;
+pub use ui::*;
+pub use watch::*;
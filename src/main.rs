@@ -15,33 +15,566 @@
 
 #[macro_use]
 extern crate clap;
-pub extern crate rustbox;
+extern crate dredge;
 
-pub mod fstree;
-pub mod os;
-pub mod ui;
+use dredge::*;
 
-pub use fstree::*;
-pub use ui::*;
+use std::io::{BufRead, IsTerminal, Write};
+use std::time::Duration;
+
+/// How often the progress display is updated when waiting for a scan.
+const PROGRESS_INTERVAL: u64 = 250; // ms
+
+/// Print a one-line summary of a scan's progress to stderr. On a terminal
+/// each report overwrites the last one; otherwise they're just lines.
+fn print_progress(progress: &Progress, names: NameDisplay) {
+    let stderr = std::io::stderr();
+    let line = format!(
+        "{} files, {} directories, {}, {} errors: {}",
+        progress.files,
+        progress.dirs,
+        UI::format_size(progress.bytes.disk),
+        progress.errors,
+        names.show(&progress.current),
+    );
+
+    let mut handle = stderr.lock();
+    let _ = if stderr.is_terminal() {
+        write!(handle, "\r\x1b[K{}", line)
+    } else {
+        writeln!(handle, "{}", line)
+    };
+}
+
+/// Build the exclude/include filter from the command line, exiting with an
+/// error if any of the patterns (or pattern files) are no good.
+fn filter(args: &clap::ArgMatches) -> Filter {
+    let fail = |message: String| -> ! {
+        eprintln!("dredge: {}", message);
+        std::process::exit(1);
+    };
+
+    let mut exclude: Vec<String> = args.values_of("EXCLUDE")
+        .map(|values| values.map(String::from).collect() )
+        .unwrap_or_default();
+
+    for file in args.values_of("EXCLUDE_FROM").into_iter().flatten() {
+        let text = std::fs::read_to_string(file)
+            .unwrap_or_else(|e| fail(format!("{}: {}", file, e)) );
+
+        // blank lines and comments are allowed
+        exclude.extend(
+            text.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#') )
+                .map(String::from)
+        );
+    }
+
+    let include: Vec<&str> = args.values_of("INCLUDE")
+        .map(|values| values.collect() )
+        .unwrap_or_default();
+
+    let compile = |source: &str| Pattern::new(source).unwrap_or_else(|e| fail(e) );
+
+    Filter {
+        exclude: exclude.iter().map(|source| compile(source) ).collect(),
+        include: include.into_iter().map(compile).collect(),
+    }
+}
+
+/// Read a saved scan from `file` (or stdin, for `-`), which can be one of
+/// our snapshots or an ncdu JSON dump. Exits with an error if it's neither.
+fn import(file: &str) -> FSTree {
+    let input: Box<dyn std::io::Read> = if file == "-" {
+        Box::new(std::io::stdin())
+    } else {
+        match std::fs::File::open(file) {
+            Ok(input) => Box::new(input),
+
+            Err(e) => {
+                eprintln!("dredge: {}: {}", file, e);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    let mut input = std::io::BufReader::new(input);
+
+    // ncdu dumps are JSON arrays, and snapshots never start with '['
+    let is_ncdu = input.fill_buf()
+        .map(|buf| buf.iter().find(|byte| !byte.is_ascii_whitespace() ) == Some(&b'[') )
+        .unwrap_or(false);
+
+    let result = if is_ncdu {
+        ncdu::read(input)
+    } else {
+        snapshot::read(input)
+    };
+
+    result.unwrap_or_else(|e| {
+        eprintln!("dredge: {}: {}", file, e);
+        std::process::exit(1);
+    })
+}
+
+/// Write `fst` out with whichever exports were asked for.
+fn export(fst: &FSTree, args: &clap::ArgMatches) {
+    if let Some(file) = args.value_of("EXPORT") {
+        write_to(file, |out| snapshot::write(fst, out) );
+    }
+
+    if let Some(file) = args.value_of("EXPORT_NCDU") {
+        write_to(file, |out| ncdu::write(fst, out) );
+    }
+}
+
+/// Hand `file` (or stdout, for `-`) to `write`, exiting with an error if
+/// anything goes wrong.
+fn write_to<F>(file: &str, write: F)
+    where F: FnOnce(Box<dyn Write>) -> std::io::Result<()>
+{
+    let result = if file == "-" {
+        write(Box::new(std::io::stdout()))
+    } else {
+        std::fs::File::create(file).and_then(|out| write(Box::new(out)) )
+    };
+
+    if let Err(e) = result {
+        eprintln!("dredge: {}: {}", file, e);
+        std::process::exit(1);
+    }
+}
+
+/// Set up the report asked for on the command line, exiting with an error
+/// if any of the options are no good.
+fn report(args: &clap::ArgMatches, size_mode: SizeMode, names: NameDisplay) -> Report {
+    let max_depth = if args.is_present("MAX_DEPTH") {
+        Some(value_t!(args, "MAX_DEPTH", usize).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
+
+    let top = if args.is_present("TOP") {
+        Some(value_t!(args, "TOP", usize).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
+
+    let threshold = args.value_of("THRESHOLD")
+        .map(|text| parse_size(text).unwrap_or_else(|e| {
+            eprintln!("dredge: {}", e);
+            std::process::exit(1);
+        }) )
+        .unwrap_or(0);
+
+    let format = match args.value_of("FORMAT") {
+        Some("json") => ReportFormat::Json,
+        Some("ndjson") => ReportFormat::Ndjson,
+        Some("csv") => ReportFormat::Csv,
+        _ => ReportFormat::Text,
+    };
+
+    let order = match args.value_of("SORT") {
+        Some("name") => ReportOrder::Name,
+        _ => ReportOrder::Size,
+    };
+
+    Report::new()
+        .format(format)
+        .max_depth(max_depth)
+        .order(order)
+        .raw_sizes(args.is_present("BYTES"))
+        .threshold(threshold)
+        .all(args.is_present("ALL"))
+        .top(top)
+        .size_mode(size_mode)
+        .names(names)
+}
+
+/// Print `report` of `fst` to stdout, followed by anything that couldn't be
+/// read on stderr. Like du, exits with an error if there was any.
+fn print_report(report: &Report, fst: &FSTree, names: NameDisplay) {
+    match report.write(fst, std::io::stdout()) {
+        // most likely piped into something like head, which has had enough
+        Err(ref e) if e.kind() == std::io::ErrorKind::BrokenPipe => (),
+
+        Err(e) => {
+            eprintln!("dredge: {}", e);
+            std::process::exit(1);
+        }
+
+        Ok(()) => (),
+    }
+
+    let errors = fst.root().errors_list();
+    for &(ref path, error) in &errors {
+        eprintln!("dredge: {}: {}", names.show(path), error);
+    }
+
+    if !errors.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Gather up the size limits given on the command line, if there are any,
+/// exiting with an error if any of them are no good.
+fn rules(args: &clap::ArgMatches) -> Option<Vec<Rule>> {
+    let fail = |message: String| -> ! {
+        eprintln!("dredge: {}", message);
+        std::process::exit(1);
+    };
+
+    let mut rules = Vec::new();
+
+    if let Some(text) = args.value_of("FAIL_IF_LARGER") {
+        rules.push(Rule::root(parse_size(text).unwrap_or_else(|e| fail(e) )));
+    }
+
+    if let Some(file) = args.value_of("RULES") {
+        let text = std::fs::read_to_string(file)
+            .unwrap_or_else(|e| fail(format!("{}: {}", file, e)) );
+
+        rules.extend(
+            Rule::parse_file(&text).unwrap_or_else(|e| fail(format!("{}: {}", file, e)) )
+        );
+    }
+
+    if args.is_present("FAIL_IF_LARGER") || args.is_present("RULES") {
+        Some(rules)
+    } else {
+        None
+    }
+}
+
+/// List everything in `fst` that's bigger than `rules` allow on stdout,
+/// followed by anything that couldn't be read (and so wasn't checked) on
+/// stderr. Exits with 1 if anything's too big, or else 2 if anything
+/// couldn't be read.
+fn enforce(rules: &[Rule], fst: &FSTree, size_mode: SizeMode, names: NameDisplay) {
+    let violations = check(fst, rules, size_mode);
+
+    for violation in &violations {
+        let mut line = format!(
+            "{}: {} is over {}",
+            names.show(&violation.path),
+            UI::format_size(violation.size),
+            UI::format_size(violation.limit),
+        );
+
+        if let Some(ref rule) = violation.rule {
+            line.push_str(&format!(" ({})", rule));
+        }
+
+        println!("{}", line);
+    }
+
+    let errors = fst.root().errors_list();
+    for &(ref path, error) in &errors {
+        eprintln!("dredge: {}: {}", names.show(path), error);
+    }
+
+    if !violations.is_empty() {
+        std::process::exit(1);
+    } else if !errors.is_empty() {
+        std::process::exit(2);
+    }
+}
 
 fn main() {
     let args = clap_app!(dredge =>
         (version: crate_version!())
         (about: r#"A utility for inspecting disk usage in directory trees.
 
-hjkl for movement, q to quit, d to delete. See https://github.com/mdunsmuir/dredge for details."#)
-        (@arg PATH: +required "The root directory to inspect")
+hjkl for movement, q to quit, d to delete, m to mark for deleting together and M to review
+and delete what's marked, a to switch between disk usage and apparent size,
+s to change the sort order, % to show allocation ratios, e to list errors,
+t to list the largest files and directories,
+r/R to rescan the current directory/selected entry. See https://github.com/mdunsmuir/dredge for details."#)
+        (@arg THREADS: -t --threads +takes_value
+            "Number of threads to scan with (defaults to the number of CPUs)")
+        (@arg LINKS: long("hard-links") +takes_value possible_value[first split]
+            "Count files with several hard links against the first link found (the default) \
+             or split them evenly between links")
+        (@arg ONE_FS: -x long("one-file-system")
+            "Don't descend into directories on other filesystems")
+        (@arg FOLLOW: -L long("follow-symlinks")
+            "Descend into symlinks to directories (without counting them towards the total)")
+        (@arg DEREFERENCE_ARGS: -H long("dereference-args")
+            "Follow the root directory if it's a symlink, but no others (the default; -L follows the rest)")
+        (@arg EXCLUDE: long("exclude") +takes_value +multiple number_of_values(1)
+            "Skip entries matching this glob pattern (may be given more than once)")
+        (@arg EXCLUDE_FROM: long("exclude-from") +takes_value +multiple number_of_values(1)
+            "Read exclude patterns from this file, one per line")
+        (@arg INCLUDE: long("include") +takes_value +multiple number_of_values(1)
+            "Don't skip entries matching this glob pattern, even if they match an exclude pattern")
+        (@arg SHOW_EXCLUDED: long("show-excluded")
+            "List excluded entries (without looking inside them) rather than hiding them")
+        (@arg APPARENT: long("apparent-size")
+            "Start out showing apparent sizes rather than disk usage (toggle with 'a')")
+        (@arg NAMES: long("names") +takes_value possible_value[escape replace]
+            "How to show names that aren't valid UTF-8: escape the odd bytes as \\xNN (the default) \
+             or replace them with \u{FFFD}")
+        (@arg WATCH: long("watch")
+            "Keep watching for changes once the scan is done, and keep up with them (Linux only)")
+        (@arg WAIT: -w --wait
+            "Finish scanning before starting the browser, showing progress in the meantime")
+        (@arg EXPORT: long("export") +takes_value conflicts_with[WATCH]
+            "Scan, save the results to this file ('-' for stdout) and exit, without starting the browser")
+        (@arg EXPORT_NCDU: long("export-ncdu") +takes_value conflicts_with[WATCH]
+            "Like --export, but in ncdu's JSON format")
+        (@arg IMPORT: long("import") +takes_value conflicts_with[PATH WATCH WAIT]
+            "Browse a scan saved with --export or --export-ncdu, or by ncdu -o, instead of scanning \
+             (read-only; '-' for stdin). With an --export option, convert it instead")
+        (@arg COMPARE: long("compare") +takes_value conflicts_with[EXPORT EXPORT_NCDU]
+            "Show what's changed since a scan saved in this file, biggest growth first \
+             (in either of the formats --import reads)")
+        (@arg REPORT: long("report") conflicts_with[WATCH COMPARE EXPORT EXPORT_NCDU]
+            "Print sizes to stdout like du instead of starting the browser (directories only, \
+             everything in a directory before the directory itself)")
+        (@arg FORMAT: long("format") +takes_value possible_value[text json ndjson csv] requires[REPORT]
+            "Write the report as text (the default), a JSON array, one JSON object per line, or CSV, \
+             with path, kind, size, total, entry count, mtime, owner and symlink target for each entry")
+        (@arg MAX_DEPTH: long("max-depth") +takes_value requires[REPORT]
+            "Only report this many levels below the root")
+        (@arg SORT: long("sort") +takes_value possible_value[size name] requires[REPORT]
+            "Report the biggest entries in each directory first (the default) or go by name")
+        (@arg BYTES: -b long("bytes") requires[REPORT]
+            "Report sizes in bytes rather than KiB, MiB etc.")
+        (@arg THRESHOLD: long("threshold") +takes_value requires[REPORT]
+            "Leave anything smaller than this (e.g. 100M) out of the report")
+        (@arg ALL: -a long("all") requires[REPORT]
+            "Report files as well as directories")
+        (@arg TOP: long("top") +takes_value requires[REPORT] conflicts_with[MAX_DEPTH SORT ALL]
+            "Only report this many of the biggest files, and directories with the most directly in them, \
+             from anywhere in the tree")
+        (@arg FAIL_IF_LARGER: long("fail-if-larger-than") +takes_value
+            conflicts_with[WATCH COMPARE REPORT EXPORT EXPORT_NCDU]
+            "Don't start the browser, but exit with an error if the whole tree is bigger than this \
+             (e.g. 10G)")
+        (@arg RULES: long("rules") +takes_value conflicts_with[WATCH COMPARE REPORT EXPORT EXPORT_NCDU]
+            "Like --fail-if-larger-than, but with limits for parts of the tree from this file, \
+             one per line (e.g. 'target/ > 5G' or 'logs/*.log > 100M')")
+        (@arg PATH: +multiple required_unless[IMPORT]
+            "The root directory to inspect. With more than one, they're shown side by side \
+             under a top level of their own")
     ).get_matches();
 
-    let path = std::path::PathBuf::from(args.value_of("PATH").unwrap());
+    let names = match args.value_of("NAMES") {
+        Some("replace") => NameDisplay::Replace,
+        _ => NameDisplay::Escape,
+    };
+
+    let size_mode = if args.is_present("APPARENT") {
+        SizeMode::Apparent
+    } else {
+        SizeMode::Disk
+    };
+
+    let base = args.value_of("COMPARE").map(import);
+
+    let report = if args.is_present("REPORT") {
+        Some(report(&args, size_mode, names))
+    } else {
+        None
+    };
+
+    let rules = rules(&args);
+
+    if let Some(file) = args.value_of("IMPORT") {
+        let fst = import(file);
+
+        if args.is_present("EXPORT") || args.is_present("EXPORT_NCDU") {
+            export(&fst, &args);
+            return;
+        }
+
+        if let Some(report) = report {
+            print_report(&report, &fst, names);
+            return;
+        }
+
+        if let Some(rules) = rules {
+            enforce(&rules, &fst, size_mode, names);
+            return;
+        }
+
+        let rustbox = rustbox::RustBox::init(Default::default()).unwrap();
+        let no_scanner = || Scanner::new();
+        let mut ui = UI::new(&rustbox, fst, None, &no_scanner, size_mode, names);
+        ui.read_only();
+
+        if let Some(base) = base {
+            ui.compare(base);
+        }
+
+        ui.event_loop();
+        return;
+    }
+
+    // the same directory twice would only be counted twice
+    let mut paths: Vec<std::path::PathBuf> = Vec::new();
+    for path in args.values_of_os("PATH").unwrap() {
+        let path = std::path::PathBuf::from(path);
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+
+    // exports only have room for one tree, and an earlier scan can only be
+    // compared with the same one
+    if paths.len() > 1 {
+        for &(arg, option) in &[("EXPORT", "--export"), ("EXPORT_NCDU", "--export-ncdu"), ("COMPARE", "--compare")] {
+            if args.is_present(arg) {
+                eprintln!("dredge: {} only works with one directory", option);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let threads = if args.is_present("THREADS") {
+        Some(value_t!(args, "THREADS", usize).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
+
+    let attribution = match args.value_of("LINKS") {
+        Some("split") => LinkAttribution::Split,
+        _ => LinkAttribution::First,
+    };
+
+    // read any --exclude-from files now, while errors can still be printed
+    let filter = filter(&args);
+    let new_scanner = || {
+        let scanner = Scanner::new()
+            .one_file_system(args.is_present("ONE_FS"))
+            .follow_symlinks(args.is_present("FOLLOW"))
+            .filter(filter.clone(), args.is_present("SHOW_EXCLUDED"))
+            .hard_links(attribution);
+        match threads {
+            Some(threads) => scanner.threads(threads),
+            None => scanner,
+        }
+    };
+
+    // a root that's a symlink is always followed, as du and ncdu do
+    for path in &paths {
+        if let Err(e) = std::fs::read_dir(path) {
+            eprintln!("dredge: {}: {}", names.show(path), e);
+            std::process::exit(1);
+        }
+    }
 
-    println!("loading...");
-    let fsts = FSTree::from_dir(&path).unwrap();
+    let interval = Duration::from_millis(PROGRESS_INTERVAL);
+
+    if args.is_present("EXPORT") || args.is_present("EXPORT_NCDU") {
+        let fst = new_scanner()
+            .on_progress(interval, |progress| print_progress(progress, names))
+            .scan_all(&paths)
+            .unwrap();
+
+        if std::io::stderr().is_terminal() {
+            eprintln!();
+        }
+
+        export(&fst, &args);
+        return;
+    }
+
+    if report.is_some() || rules.is_some() {
+        // this is likely to be running from cron or a script, where nobody
+        // wants a log of the scan's progress
+        let scanner = if std::io::stderr().is_terminal() {
+            new_scanner().on_progress(interval, |progress| print_progress(progress, names))
+        } else {
+            new_scanner()
+        };
+
+        let fst = scanner.scan_all(&paths).unwrap();
+
+        if std::io::stderr().is_terminal() {
+            eprint!("\r\x1b[K");
+        }
+
+        if let Some(report) = report {
+            print_report(&report, &fst, names);
+        }
+
+        if let Some(rules) = rules {
+            enforce(&rules, &fst, size_mode, names);
+        }
+
+        return;
+    }
+
+    // the watcher's scanner can end up borrowing this, so it has to be
+    // declared first, even though it's set up last
+    let rustbox;
+
+    // set up now so that any trouble can still be reported
+    let watcher = if args.is_present("WATCH") {
+        match Watcher::new(new_scanner()) {
+            Ok(watcher) => Some(watcher),
+
+            Err(e) => {
+                eprintln!("dredge: can't watch for changes: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let wait = args.is_present("WAIT");
+
+    // if our output isn't going to a terminal, progress goes to stderr
+    // instead of the screen. That has to happen before rustbox starts
+    // buffering stderr.
+    let scanned = if wait && !std::io::stdout().is_terminal() {
+        let fsts = new_scanner()
+            .on_progress(interval, |progress| print_progress(progress, names))
+            .scan_all(&paths);
+
+        if std::io::stderr().is_terminal() {
+            eprintln!();
+        }
+
+        Some(fsts.unwrap())
+    } else {
+        None
+    };
 
     let mut opts = rustbox::InitOptions::default();
     opts.buffer_stderr = true;
-    let rustbox = rustbox::RustBox::init(opts).unwrap();
+    rustbox = rustbox::RustBox::init(opts).unwrap();
+
+    let mut ui = match scanned {
+        Some(fsts) => UI::new(&rustbox, fsts, None, &new_scanner, size_mode, names),
+
+        None if wait => {
+            let fsts = new_scanner()
+                .on_progress(interval, |progress|
+                    UI::draw_progress(&rustbox, &paths, progress, names)
+                )
+                .scan_all(&paths)
+                .unwrap();
+
+            UI::new(&rustbox, fsts, None, &new_scanner, size_mode, names)
+        }
+
+        None => {
+            let (fsts, scan) = new_scanner().start_all(&paths);
+            UI::new(&rustbox, fsts, Some(scan), &new_scanner, size_mode, names)
+        }
+    };
+
+    if let Some(watcher) = watcher {
+        ui.watch(watcher);
+    }
+
+    if let Some(base) = base {
+        ui.compare(base);
+    }
 
-    let mut ui = UI::new(&rustbox, fsts);
     ui.event_loop();
 }
@@ -0,0 +1,641 @@
+// Copyright (C) 2016  Michael Dunsmuir
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Reading and writing ncdu's JSON export format
+// (https://dev.yorhel.nl/ncdu/jsonfmt), so that scans can go back and forth
+// between the two.
+//
+// A dump is `[1, <minor version>, {<metadata>}, <root directory>]`, where a
+// directory is an array of its own info object followed by its entries:
+// objects for anything else, arrays for more directories. The root
+// directory's name is its whole path.
+
+extern crate std;
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::fstree::{Contents, Entry, EntryKind, FSTree, Node, Share, Size, SpecialKind};
+
+/// The only major version of the format there is.
+const MAJOR_VERSION: u64 = 1;
+
+/// The minor version we write, which is the one with `mtime` and `nlink`.
+const MINOR_VERSION: u64 = 2;
+
+/// Write the whole of `fst` to `out`.
+///
+/// ncdu has no idea of followed symlinks, so they're written as plain
+/// symlinks and what's behind them is left out, as it would be from ncdu's
+/// own totals. Files with several hard links are written with however much
+/// of their size was counted here, rather than marked as hard links, since
+/// we don't keep the inode numbers ncdu would need to count them once.
+pub fn write<W: Write>(fst: &FSTree, out: W) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() )
+        .unwrap_or(0);
+
+    writeln!(
+        out,
+        "[{},{},{{\"progname\":\"dredge\",\"progver\":\"{}\",\"timestamp\":{}}},",
+        MAJOR_VERSION, MINOR_VERSION, env!("CARGO_PKG_VERSION"), timestamp,
+    )?;
+
+    // the root is named by its whole path
+    write!(out, "[{{\"name\":")?;
+    write_string(&mut out, fst.path().as_os_str().as_bytes())?;
+    write_extended(&mut out, fst.root())?;
+    write!(out, "}}")?;
+
+    write_contents(&mut out, fst.root())?;
+    writeln!(out, "]]")?;
+
+    out.flush()
+}
+
+fn write_contents<W: Write>(out: &mut W, dir: Node) -> io::Result<()> {
+    for node in dir.children() {
+        writeln!(out, ",")?;
+
+        if node.kind() == EntryKind::Dir {
+            write!(out, "[")?;
+            write_info(out, node)?;
+            write_contents(out, node)?;
+            write!(out, "]")?;
+        } else {
+            write_info(out, node)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_info<W: Write>(out: &mut W, node: Node) -> io::Result<()> {
+    write!(out, "{{\"name\":")?;
+    write_string(out, node.name().as_bytes())?;
+
+    match node.kind() {
+        EntryKind::Dir => (),
+
+        EntryKind::File => {
+            write_size(out, node.size().unwrap_or_default())?;
+
+            if let Some(links) = node.links().filter(|&links| links > 1 ) {
+                write!(out, ",\"nlink\":{}", links)?;
+            }
+        }
+
+        EntryKind::Symlink | EntryKind::Cycle | EntryKind::Special(_) => {
+            write_size(out, node.size().unwrap_or_default())?;
+            write!(out, ",\"notreg\":true")?;
+        }
+
+        EntryKind::Linked => write!(out, ",\"notreg\":true")?,
+        EntryKind::MountPoint => write!(out, ",\"excluded\":\"otherfs\"")?,
+        EntryKind::Excluded => write!(out, ",\"excluded\":\"pattern\"")?,
+        EntryKind::Bad => write!(out, ",\"read_error\":true")?,
+    }
+
+    write_extended(out, node)?;
+    write!(out, "}}")
+}
+
+// the parts of what ncdu's extended mode (`-e`) writes that we keep track of
+fn write_extended<W: Write>(out: &mut W, node: Node) -> io::Result<()> {
+    if let Some(owner) = node.owner() {
+        write!(out, ",\"uid\":{}", owner)?;
+    }
+
+    if let Some(mtime) = node.mtime() {
+        write!(out, ",\"mtime\":{}", mtime)?;
+    }
+
+    Ok(())
+}
+
+fn write_size<W: Write>(out: &mut W, size: Size) -> io::Result<()> {
+    write!(out, ",\"asize\":{},\"dsize\":{}", size.apparent, size.disk)
+}
+
+// like ncdu, names go out as the bytes they are, whether or not they're
+// UTF-8, with just enough escaped to keep the JSON in one piece
+fn write_string<W: Write>(out: &mut W, bytes: &[u8]) -> io::Result<()> {
+    out.write_all(b"\"")?;
+
+    for &byte in bytes {
+        match byte {
+            b'"' => out.write_all(b"\\\"")?,
+            b'\\' => out.write_all(b"\\\\")?,
+            0x00..=0x1f | 0x7f => write!(out, "\\u{:04x}", byte)?,
+            _ => out.write_all(&[byte])?,
+        }
+    }
+
+    out.write_all(b"\"")
+}
+
+/// Read an ncdu JSON dump into a tree.
+///
+/// Entries ncdu marks as hard links are counted once, against the first
+/// link in the file. Anything that isn't a regular file comes back as a
+/// symlink, unless the dump was made with ncdu's extended mode (`-e`), in
+/// which case the file mode says what it really is.
+pub fn read<R: BufRead>(input: R) -> io::Result<FSTree> {
+    let mut parser = Parser { input };
+
+    parser.expect(b'[')?;
+    let major = parser.value()?.as_u64();
+    if major != Some(MAJOR_VERSION) {
+        return Err(invalid("not an ncdu dump, or an unsupported version of one"));
+    }
+
+    // the minor version and the metadata don't tell us anything we need
+    parser.expect(b',')?;
+    parser.value()?;
+    parser.expect(b',')?;
+    parser.value()?;
+    parser.expect(b',')?;
+
+    let mut seen = HashSet::new();
+    let (top, level) = parser.dir(0, &mut seen)?;
+
+    // a dump that stops short can still look like a whole tree up to here
+    parser.expect(b']')?;
+
+    let root = PathBuf::from(top.name().to_os_string());
+    let name = root.file_name().unwrap_or(root.as_os_str()).to_os_string();
+    let top = Entry::new(name, EntryKind::Dir, Size::default())
+        .with_mtime(level.mtime)
+        .with_owner(level.owner);
+    let mut fst = FSTree::from_entry(root, top);
+
+    graft(&mut fst, &mut Vec::new(), level);
+    Ok(fst)
+}
+
+// one directory's worth of a dump, with the levels below it in the same
+// order as the entries they belong to
+struct Level {
+    mtime: Option<i64>,
+    owner: Option<u32>,
+    entries: Vec<Entry>,
+    subdirs: Vec<Level>,
+}
+
+fn graft(fst: &mut FSTree, names: &mut Vec<OsString>, level: Level) {
+    let dirs: Vec<OsString> = level.entries.iter()
+        .filter(|entry| entry.has_contents() )
+        .map(|entry| entry.name().to_os_string() )
+        .collect();
+
+    fst.graft(names, Ok(Contents::from_entries(level.entries)));
+
+    for (name, subdir) in dirs.into_iter().zip(level.subdirs) {
+        names.push(name);
+        graft(fst, names, subdir);
+        names.pop();
+    }
+}
+
+// just enough JSON for reading dumps. Numbers are kept as they were written
+// and only turned into numbers when they're wanted, so that nothing's lost,
+// and arrays outside the tree itself are skipped over
+enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(Vec<u8>),
+    Array,
+    Object(Vec<(Vec<u8>, Json)>),
+}
+
+impl Json {
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        if let Json::Object(ref fields) = *self {
+            fields.iter()
+                .find(|(name, _)| name == key.as_bytes() )
+                .map(|(_, value)| value )
+        } else {
+            None
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        if let Json::Number(ref number) = *self { number.parse().ok() } else { None }
+    }
+
+    fn as_i64(&self) -> Option<i64> {
+        if let Json::Number(ref number) = *self { number.parse().ok() } else { None }
+    }
+
+    fn is_true(&self) -> bool {
+        if let Json::Bool(value) = *self { value } else { false }
+    }
+
+    fn field_u64(&self, key: &str) -> Option<u64> {
+        self.get(key).and_then(Json::as_u64)
+    }
+
+    fn field_true(&self, key: &str) -> bool {
+        self.get(key).map(Json::is_true).unwrap_or(false)
+    }
+}
+
+/// The file type bits of a `mode`, and the ones we care about.
+const S_IFMT: u64 = 0o170000;
+const S_IFLNK: u64 = 0o120000;
+const S_IFREG: u64 = 0o100000;
+const S_IFDIR: u64 = 0o040000;
+const S_IFIFO: u64 = 0o010000;
+const S_IFSOCK: u64 = 0o140000;
+const S_IFBLK: u64 = 0o060000;
+const S_IFCHR: u64 = 0o020000;
+
+struct Parser<R: BufRead> {
+    input: R,
+}
+
+impl<R: BufRead> Parser<R> {
+
+    // a directory array, given the device of the one it's in (entries
+    // only say when it changes)
+    fn dir(&mut self, dev: u64, seen: &mut HashSet<(u64, u64)>) -> io::Result<(Entry, Level)> {
+        self.expect(b'[')?;
+        let info = self.value()?;
+        let dev = info.field_u64("dev").unwrap_or(dev);
+
+        let mut level = Level {
+            mtime: info.get("mtime").and_then(Json::as_i64),
+            owner: owner(&info),
+            entries: Vec::new(),
+            subdirs: Vec::new(),
+        };
+
+        while self.next_item(b']')? {
+            if self.peek_token()? == b'[' {
+                let (entry, subdir) = self.dir(dev, seen)?;
+                if entry.has_contents() {
+                    level.subdirs.push(subdir);
+                }
+                level.entries.push(entry);
+            } else {
+                let info = self.value()?;
+                level.entries.push(entry(&info, dev, seen)?);
+            }
+        }
+
+        // a directory ncdu couldn't read anything from at all is as
+        // unreadable as it gets
+        let entry = if info.field_true("read_error") && level.entries.is_empty() {
+            Entry::bad(name(&info)?, io::ErrorKind::Other)
+        } else {
+            Entry::new(name(&info)?, EntryKind::Dir, Size::default())
+        };
+
+        Ok((entry.with_mtime(level.mtime).with_owner(level.owner), level))
+    }
+
+    // after the first item in an array, whether there's another one
+    // (having skipped the comma) or the array's over
+    fn next_item(&mut self, close: u8) -> io::Result<bool> {
+        match self.token()? {
+            b',' => Ok(true),
+            byte if byte == close => Ok(false),
+            byte => Err(unexpected(byte)),
+        }
+    }
+
+    fn value(&mut self) -> io::Result<Json> {
+        match self.peek_token()? {
+            b'{' => {
+                self.next()?;
+                let mut fields = Vec::new();
+
+                if self.peek_token()? == b'}' {
+                    self.next()?;
+                } else {
+                    loop {
+                        self.skip_space()?;
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        fields.push((key, self.value()?));
+
+                        if !self.next_item(b'}')? {
+                            break;
+                        }
+                    }
+                }
+
+                Ok(Json::Object(fields))
+            }
+
+            b'[' => {
+                self.next()?;
+
+                if self.peek_token()? == b']' {
+                    self.next()?;
+                } else {
+                    loop {
+                        self.value()?;
+
+                        if !self.next_item(b']')? {
+                            break;
+                        }
+                    }
+                }
+
+                Ok(Json::Array)
+            }
+
+            b'"' => self.string().map(Json::String),
+
+            b't' => self.word("true").map(|_| Json::Bool(true) ),
+            b'f' => self.word("false").map(|_| Json::Bool(false) ),
+            b'n' => self.word("null").map(|_| Json::Null ),
+
+            _ => {
+                let mut number = String::new();
+
+                while let Some(byte) = self.peek()? {
+                    match byte {
+                        b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E' => {
+                            number.push(byte as char);
+                            self.next()?;
+                        }
+
+                        _ => break,
+                    }
+                }
+
+                if number.is_empty() {
+                    Err(unexpected(self.next()?))
+                } else {
+                    Ok(Json::Number(number))
+                }
+            }
+        }
+    }
+
+    fn string(&mut self) -> io::Result<Vec<u8>> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+
+        loop {
+            match self.next()? {
+                b'"' => return Ok(bytes),
+
+                b'\\' => match self.next()? {
+                    b'b' => bytes.push(0x08),
+                    b'f' => bytes.push(0x0c),
+                    b'n' => bytes.push(b'\n'),
+                    b'r' => bytes.push(b'\r'),
+                    b't' => bytes.push(b'\t'),
+
+                    b'u' => {
+                        let mut code = self.hex()?;
+
+                        // a surrogate pair, for anything outside the BMP
+                        if (0xd800..0xdc00).contains(&code) {
+                            self.expect(b'\\')?;
+                            self.expect(b'u')?;
+                            let low = self.hex()?;
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                        }
+
+                        let c = std::char::from_u32(code).unwrap_or('\u{fffd}');
+                        let mut buf = [0; 4];
+                        bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    }
+
+                    byte => bytes.push(byte),
+                },
+
+                byte => bytes.push(byte),
+            }
+        }
+    }
+
+    fn hex(&mut self) -> io::Result<u32> {
+        let mut code = 0;
+
+        for _ in 0..4 {
+            let byte = self.next()?;
+            let digit = (byte as char).to_digit(16).ok_or_else(|| unexpected(byte) )?;
+            code = code * 16 + digit;
+        }
+
+        Ok(code)
+    }
+
+    fn word(&mut self, word: &str) -> io::Result<()> {
+        for &byte in word.as_bytes() {
+            self.expect(byte)?;
+        }
+
+        Ok(())
+    }
+
+    fn expect(&mut self, expected: u8) -> io::Result<()> {
+        match self.token()? {
+            byte if byte == expected => Ok(()),
+            byte => Err(unexpected(byte)),
+        }
+    }
+
+    // the next byte that isn't whitespace, which is used up
+    fn token(&mut self) -> io::Result<u8> {
+        self.skip_space()?;
+        self.next()
+    }
+
+    // the next byte that isn't whitespace, which isn't
+    fn peek_token(&mut self) -> io::Result<u8> {
+        self.skip_space()?;
+        self.peek()?.ok_or_else(|| io::ErrorKind::UnexpectedEof.into() )
+    }
+
+    fn skip_space(&mut self) -> io::Result<()> {
+        while let Some(byte) = self.peek()? {
+            if byte.is_ascii_whitespace() {
+                self.next()?;
+            } else {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.fill_buf()?.first().cloned())
+    }
+
+    fn next(&mut self) -> io::Result<u8> {
+        let byte = self.peek()?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        self.input.consume(1);
+        Ok(byte)
+    }
+}
+
+fn name(info: &Json) -> io::Result<OsString> {
+    match info.get("name") {
+        Some(Json::String(name)) => Ok(OsString::from_vec(name.clone())),
+        _ => Err(invalid("entry without a name")),
+    }
+}
+
+// anything that isn't a directory ncdu went into
+fn entry(info: &Json, dev: u64, seen: &mut HashSet<(u64, u64)>) -> io::Result<Entry> {
+    let name = name(info)?;
+    let mtime = info.get("mtime").and_then(Json::as_i64);
+    let owner = owner(info);
+
+    let size = Size {
+        disk: info.field_u64("dsize").unwrap_or(0),
+        apparent: info.field_u64("asize").unwrap_or(0),
+    };
+
+    if let Some(Json::String(reason)) = info.get("excluded") {
+        let kind = match &reason[..] {
+            b"otherfs" | b"kernfs" => EntryKind::MountPoint,
+            _ => EntryKind::Excluded,
+        };
+
+        return Ok(Entry::new(name, kind, size).with_mtime(mtime).with_owner(owner));
+    }
+
+    if info.field_true("read_error") {
+        return Ok(Entry::bad(name, io::ErrorKind::Other).with_mtime(mtime).with_owner(owner));
+    }
+
+    let kind = match info.field_u64("mode").map(|mode| mode & S_IFMT ) {
+        Some(S_IFREG) => EntryKind::File,
+        Some(S_IFLNK) => EntryKind::Symlink,
+        Some(S_IFIFO) => EntryKind::Special(SpecialKind::Fifo),
+        Some(S_IFSOCK) => EntryKind::Special(SpecialKind::Socket),
+        Some(S_IFBLK) => EntryKind::Special(SpecialKind::BlockDevice),
+        Some(S_IFCHR) => EntryKind::Special(SpecialKind::CharDevice),
+
+        // a directory ncdu didn't go into, for whatever reason
+        Some(S_IFDIR) => EntryKind::Excluded,
+
+        _ if info.field_true("notreg") => EntryKind::Symlink,
+        _ => EntryKind::File,
+    };
+
+    let mut entry = Entry::new(name, kind, size).with_mtime(mtime).with_owner(owner);
+
+    if kind == EntryKind::File {
+        let links = info.field_u64("nlink").unwrap_or(1);
+
+        if info.field_true("hlnkc") {
+            let inode = (info.field_u64("dev").unwrap_or(dev), info.field_u64("ino").unwrap_or(0));
+            let share = if seen.insert(inode) { Share::Whole } else { Share::Duplicate };
+            entry = entry.with_links(std::cmp::max(links, 2), share);
+        } else {
+            entry = entry.with_links(links, Share::Whole);
+        }
+    }
+
+    Ok(entry)
+}
+
+fn owner(info: &Json) -> Option<u32> {
+    info.field_u64("uid").map(|uid| uid as u32 )
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn unexpected(byte: u8) -> io::Error {
+    invalid(&format!("unexpected {:?} in ncdu dump", byte as char))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    const DUMP: &str = r#"[1,2,{"progname":"ncdu","progver":"1.19","timestamp":1700000000},
+[{"name":"/data","dev":1},
+{"name":"a","asize":100,"dsize":4096},
+[{"name":"sub"},
+{"name":"b","asize":5000,"dsize":8192},
+{"name":"x","asize":9000,"dsize":12288,"ino":7,"hlnkc":true,"nlink":2},
+{"name":"y","asize":9000,"dsize":12288,"ino":7,"hlnkc":true,"nlink":2}],
+{"name":"skipped","excluded":"pattern"},
+{"name":"broken","read_error":true}]]
+"#;
+
+    #[test]
+    fn import() {
+        let fst = read(DUMP.as_bytes()).unwrap();
+        let names = |names: &[&str]| names.iter().map(OsString::from).collect::<Vec<_>>();
+
+        assert_eq!(fst.path(), Path::new("/data"));
+        assert_eq!(fst.root().size(), Some(Size { disk: 4096 + 8192 + 12288, apparent: 100 + 5000 + 9000 }));
+
+        let sub = fst.entries(&names(&["sub"])).unwrap();
+        assert_eq!(sub.kind(), EntryKind::Dir);
+        assert_eq!(sub.size(), Some(Size { disk: 8192 + 12288, apparent: 5000 + 9000 }));
+
+        assert_eq!(fst.entries(&names(&["a"])).unwrap().kind(), EntryKind::File);
+        assert_eq!(fst.entries(&names(&["skipped"])).unwrap().kind(), EntryKind::Excluded);
+        assert_eq!(fst.entries(&names(&["broken"])).unwrap().kind(), EntryKind::Bad);
+    }
+
+    #[test]
+    fn round_trip() {
+        let fst = read(DUMP.as_bytes()).unwrap();
+        let mut written = Vec::new();
+        write(&fst, &mut written).unwrap();
+
+        let read_back = read(&written[..]).unwrap();
+        assert_eq!(read_back.path(), fst.path());
+        assert_eq!(read_back.root().size(), fst.root().size());
+    }
+
+    #[test]
+    fn malformed() {
+        let dumps = [
+            "",
+            "not json",
+            "{}",
+            "[2,0,{},[{\"name\":\"/\"}]]",
+            "[1,2,{},[{\"name\":\"/\"},{\"asize\":1}]]",
+            "[1,2,{},[{\"name\":\"/\"},{\"name\":\"a\",]]",
+            "[1,2,{},[{\"name\":\"/\"},{\"name\":\"a\"}",
+            "[1,2,{},[{\"name\":\"/\"},{\"name\":\"a\\u12\"}]]",
+        ];
+
+        for dump in &dumps {
+            assert!(read(dump.as_bytes()).is_err(), "{:?}", dump);
+        }
+
+        // every cut short version of a good dump is a bad one
+        let trimmed = DUMP.trim_end();
+        for len in 0..trimmed.len() {
+            assert!(read(&trimmed.as_bytes()[..len]).is_err(), "{:?}", &trimmed[..len]);
+        }
+    }
+}
@@ -14,6 +14,7 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::fs::Metadata;
+use super::fstree::SpecialKind;
 
 /// I'm still not sure I totally understand the implications of hardcoding
 /// this to 512, but Google says I'm not the only one doing it so I don't
@@ -46,4 +47,62 @@ pub fn size(metadata: &Metadata) -> u64 {
     metadata.blocks() * DEVICE_BLOCKSIZE
 }
 
+/// The size in bytes as reported by `ls -l` and friends, regardless of how
+/// much space is actually allocated.
+#[cfg(target_family = "unix")]
+pub fn apparent_size(metadata: &Metadata) -> u64 {
+    metadata.size()
+}
+
+/// The (device, inode) pair that identifies the file behind a path, so that
+/// hard links to the same file can be recognized.
+#[cfg(target_family = "unix")]
+pub fn inode(metadata: &Metadata) -> (u64, u64) {
+    (metadata.dev(), metadata.ino())
+}
+
+/// The device the file lives on, for spotting filesystem boundaries.
+#[cfg(target_family = "unix")]
+pub fn device(metadata: &Metadata) -> u64 {
+    metadata.dev()
+}
+
+/// The number of hard links to the file.
+#[cfg(target_family = "unix")]
+pub fn links(metadata: &Metadata) -> u64 {
+    metadata.nlink()
+}
+
+/// When the file was last modified, in seconds since the epoch.
+#[cfg(target_family = "unix")]
+pub fn mtime(metadata: &Metadata) -> i64 {
+    metadata.mtime()
+}
+
+/// The user ID of the file's owner.
+#[cfg(target_family = "unix")]
+pub fn owner(metadata: &Metadata) -> u32 {
+    metadata.uid()
+}
+
+/// What kind of special file this is, if it's one at all.
+#[cfg(target_family = "unix")]
+pub fn special_kind(metadata: &Metadata) -> Option<SpecialKind> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = metadata.file_type();
+
+    if file_type.is_fifo() {
+        Some(SpecialKind::Fifo)
+    } else if file_type.is_socket() {
+        Some(SpecialKind::Socket)
+    } else if file_type.is_block_device() {
+        Some(SpecialKind::BlockDevice)
+    } else if file_type.is_char_device() {
+        Some(SpecialKind::CharDevice)
+    } else {
+        None
+    }
+}
+
 // windows... coming soon????
@@ -0,0 +1,380 @@
+// Copyright (C) 2016  Michael Dunsmuir
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate std;
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::str;
+
+#[derive(Clone)]
+enum Token {
+    Literal(u8),
+    AnyChar,    // ?
+    Star,       // *, which stops at slashes
+    DoubleStar, // **, which doesn't
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+/// A shell-style glob pattern. Names don't have to be valid UTF-8 to be
+/// matched: a byte that isn't part of a proper UTF-8 character counts as a
+/// character of its own.
+///
+/// `?` matches any one character and `*` any run of characters, neither of
+/// them crossing a `/`. `**` matches anything at all, slashes included.
+/// `[abc]`, `[a-z]` and `[!abc]` match character classes, and a backslash
+/// matches the next character literally.
+///
+/// A pattern with no `/` in it is matched against entry names, so `*.o`
+/// matches object files in any directory. One with a `/` is matched against
+/// the entry's path from the directory the scan started in, e.g. `a/sub`
+/// (or `/a/sub`, the same thing) or `**/target/debug`.
+#[derive(Clone)]
+pub struct Pattern {
+    source: String,
+    tokens: Vec<Token>,
+    whole_path: bool,
+}
+
+impl Pattern {
+
+    pub fn new(source: &str) -> Result<Self, String> {
+        let bytes = source.as_bytes();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+
+        while i < bytes.len() {
+            let token = match bytes[i] {
+                b'?' => Token::AnyChar,
+
+                b'*' if bytes.get(i + 1) == Some(&b'*') => {
+                    i += 1;
+                    Token::DoubleStar
+                }
+
+                b'*' => Token::Star,
+
+                b'\\' => {
+                    i += 1;
+                    match bytes.get(i) {
+                        Some(&b) => Token::Literal(b),
+                        None => return Err(format!("trailing backslash in pattern '{}'", source)),
+                    }
+                }
+
+                b'[' => {
+                    let (token, end) = Self::parse_class(source, i).ok_or_else(||
+                        format!("unclosed '[' in pattern '{}'", source)
+                    )?;
+
+                    i = end;
+                    token
+                }
+
+                b => Token::Literal(b),
+            };
+
+            tokens.push(token);
+            i += 1;
+        }
+
+        Ok(Pattern {
+            source: source.to_string(),
+            tokens,
+            whole_path: source.contains('/'),
+        })
+    }
+
+    // parse the class starting at the `[` at byte `start`, returning the
+    // token and the byte index of the closing `]`
+    fn parse_class(source: &str, start: usize) -> Option<(Token, usize)> {
+        let mut chars = source[start + 1..].char_indices()
+            .map(|(i, c)| (start + 1 + i, c) )
+            .peekable();
+
+        let negated = chars.peek().map(|&(_, c)| c == '!' ).unwrap_or(false);
+        if negated {
+            chars.next();
+        }
+
+        let mut ranges = Vec::new();
+        let mut first = true;
+
+        while let Some((i, c)) = chars.next() {
+            // a `]` right at the start is part of the class, not the end of it
+            if c == ']' && !first {
+                return Some((Token::Class { negated, ranges }, i));
+            }
+
+            // a `-` just before the `]` is a plain `-`
+            let mut ahead = chars.clone();
+            match (ahead.next(), ahead.next()) {
+                (Some((_, '-')), Some((_, hi))) if hi != ']' => {
+                    ranges.push((c, hi));
+                    chars = ahead;
+                }
+
+                _ => ranges.push((c, c)),
+            }
+
+            first = false;
+        }
+
+        None
+    }
+
+    /// Check the pattern against an entry, by name or by path as described
+    /// above. `relative` is the entry's path from where the scan started.
+    pub fn matches_entry(&self, name: &OsStr, relative: &Path) -> bool {
+        if self.whole_path {
+            // `/a/sub` and `**/target` both want a `/` in front to match at
+            // the top, and `a/sub` doesn't
+            if self.source.starts_with('/') || self.source.starts_with("**") {
+                let mut path = b"/".to_vec();
+                path.extend_from_slice(relative.as_os_str().as_bytes());
+                Self::match_tokens(&self.tokens, &path)
+            } else {
+                self.matches(relative.as_os_str())
+            }
+        } else {
+            self.matches(name)
+        }
+    }
+
+    pub fn matches(&self, text: &OsStr) -> bool {
+        Self::match_tokens(&self.tokens, text.as_bytes())
+    }
+
+    // the usual glob matcher that only ever goes back to the last star,
+    // rather than trying every way of splitting the text. A `*` can't go
+    // past a slash, so when the last one gets stuck that way it's the last
+    // `**` that has to take another character instead
+    fn match_tokens(tokens: &[Token], text: &[u8]) -> bool {
+        let (mut t, mut x) = (0, 0);
+        let mut star: Option<(usize, usize)> = None;
+        let mut double_star: Option<(usize, usize)> = None;
+
+        loop {
+            let matched = match tokens.get(t) {
+                Some(&Token::Star) => {
+                    star = Some((t, x));
+                    t += 1;
+                    continue;
+                }
+
+                Some(&Token::DoubleStar) => {
+                    double_star = Some((t, x));
+                    star = None;
+                    t += 1;
+                    continue;
+                }
+
+                None if x == text.len() => return true,
+                None => false,
+
+                Some(&Token::Literal(b)) if text.get(x) == Some(&b) => {
+                    x += 1;
+                    true
+                }
+
+                Some(&Token::Literal(_)) => false,
+
+                Some(token) => match next_char(&text[x..]) {
+                    Some((c, len)) if text[x] != b'/' => {
+                        let matched = match *token {
+                            Token::Class { negated, ref ranges } => {
+                                let in_class = c.map(|c|
+                                    ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi )
+                                ).unwrap_or(false);
+
+                                in_class != negated
+                            }
+
+                            _ => true, // `?`
+                        };
+
+                        x += len;
+                        matched
+                    }
+
+                    _ => false,
+                },
+            };
+
+            if matched {
+                t += 1;
+                continue;
+            }
+
+            // give the last star one more character, if it can take it
+            if let Some((star_t, star_x)) = star {
+                if let Some((_, len)) = next_char(&text[star_x..]).filter(|_| text[star_x] != b'/' ) {
+                    star = Some((star_t, star_x + len));
+                    t = star_t + 1;
+                    x = star_x + len;
+                    continue;
+                }
+            }
+
+            match double_star {
+                Some((star_t, star_x)) if star_x < text.len() => {
+                    let len = next_char(&text[star_x..]).map(|(_, len)| len ).unwrap_or(1);
+                    double_star = Some((star_t, star_x + len));
+                    star = None;
+                    t = star_t + 1;
+                    x = star_x + len;
+                }
+
+                _ => return false,
+            }
+        }
+    }
+}
+
+// the character at the start of `text` and how many bytes it takes up, or
+// `None` for the character if those bytes aren't valid UTF-8, in which case
+// it's just the one byte
+fn next_char(text: &[u8]) -> Option<(Option<char>, usize)> {
+    let len = match *text.first()? {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Some((None, 1)),
+    };
+
+    match text.get(..len).and_then(|bytes| str::from_utf8(bytes).ok() ) {
+        Some(c) => Some((c.chars().next(), len)),
+        None => Some((None, 1)),
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// Decides which entries a scan should skip. An entry is excluded if it
+/// matches any of the `exclude` patterns and none of the `include` ones.
+#[derive(Clone, Default)]
+pub struct Filter {
+    pub exclude: Vec<Pattern>,
+    pub include: Vec<Pattern>,
+}
+
+impl Filter {
+
+    /// Whether to skip the entry called `name`, at `relative` from where the
+    /// scan started.
+    pub fn excludes(&self, name: &OsStr, relative: &Path) -> bool {
+        self.exclude.iter().any(|pattern| pattern.matches_entry(name, relative) )
+            && !self.include.iter().any(|pattern| pattern.matches_entry(name, relative) )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Pattern::new(pattern).unwrap().matches(OsStr::new(text))
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*.o", "main.o"));
+        assert!(matches("*.o", ".o"));
+        assert!(!matches("*.o", "main.oo"));
+        assert!(!matches("*.o", "src/main.o"));
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?.txt", ".txt"));
+        assert!(!matches("a?b", "a/b"));
+        assert!(matches("**/target", "/home/me/code/target"));
+        assert!(matches("/a/**/c", "/a/b/b/c"));
+        assert!(!matches("/a/*/c", "/a/b/b/c"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("[abc]x", "bx"));
+        assert!(!matches("[abc]x", "dx"));
+        assert!(matches("file[0-9]", "file7"));
+        assert!(!matches("file[0-9]", "filex"));
+        assert!(matches("[!a-c]", "d"));
+        assert!(!matches("[!a-c]", "b"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "x"));
+    }
+
+    #[test]
+    fn bad_patterns() {
+        assert!(Pattern::new("[abc").is_err());
+        assert!(Pattern::new("abc\\").is_err());
+    }
+
+    #[test]
+    fn names_and_paths() {
+        let name = Pattern::new("*.o").unwrap();
+        let path = Pattern::new("tmp/*.o").unwrap();
+        let (file, at) = (OsStr::new("x.o"), Path::new("home/x.o"));
+
+        assert!(name.matches_entry(file, at));
+        assert!(!path.matches_entry(file, at));
+        assert!(path.matches_entry(file, Path::new("tmp/x.o")));
+        assert!(!path.matches_entry(file, Path::new("a/tmp/x.o")));
+        assert!(Pattern::new("/tmp/*.o").unwrap().matches_entry(file, Path::new("tmp/x.o")));
+
+        let anywhere = Pattern::new("**/target").unwrap();
+        assert!(anywhere.matches_entry(OsStr::new("target"), Path::new("target")));
+        assert!(anywhere.matches_entry(OsStr::new("target"), Path::new("a/b/target")));
+    }
+
+    #[test]
+    fn non_utf8_names() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let name = OsString::from_vec(vec![b'a', 0xff, b'.', b'o']);
+        assert!(Pattern::new("*.o").unwrap().matches(&name));
+        assert!(Pattern::new("a?.o").unwrap().matches(&name));
+        assert!(Pattern::new("a[!x].o").unwrap().matches(&name));
+        assert!(!Pattern::new("a??.o").unwrap().matches(&name));
+    }
+
+    #[test]
+    fn whole_characters() {
+        assert!(matches("?.txt", "é.txt"));
+        assert!(!matches("??.txt", "é.txt"));
+        assert!(matches("[é]", "é"));
+        assert!(matches("[à-ü]x", "éx"));
+        assert!(!matches("[!é]", "é"));
+        assert!(matches("*?", "日本"));
+    }
+
+    #[test]
+    fn lots_of_stars() {
+        // this used to take forever, trying every way of placing the stars
+        let text = "a".repeat(100);
+        assert!(!matches(&format!("{}b", "*a".repeat(20)), &text));
+        assert!(!matches(&format!("{}b", "**a".repeat(20)), &text));
+        assert!(matches("**/a*b/**/c", "/x/y/ab/ayb/z/c"));
+        assert!(!matches("**/a*b/c", "/x/ay/b/c"));
+    }
+}
@@ -0,0 +1,573 @@
+// Copyright (C) 2016  Michael Dunsmuir
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Plain text reports of a scanned tree, for when there's no one around to
+// look at the browser.
+
+extern crate std;
+
+use std::io::{self, BufWriter, Write};
+
+use super::fstree::{EntryKind, FSTree, Node, SizeMode, SpecialKind};
+use super::top::{Largest, Ranked};
+use super::ui::{NameDisplay, UI};
+
+/// The order entries in the same directory are reported in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReportOrder {
+    /// Biggest first.
+    Size,
+
+    /// By name, as `ls` would have them.
+    Name,
+}
+
+/// How a report is written out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReportFormat {
+    /// A size and a path on each line, separated by a tab, like `du`.
+    Text,
+
+    /// One JSON array, with an object for each entry.
+    Json,
+
+    /// A JSON object for each entry, one per line.
+    Ndjson,
+
+    /// CSV, with a header line naming the fields.
+    Csv,
+}
+
+/// The fields each entry has in the formats other than `Text`, in the order
+/// they're written in.
+const FIELDS: [&str; 9] = [
+    "path", "kind", "apparent_size", "allocated_size", "total", "entries",
+    "mtime", "owner", "target",
+];
+
+/// Writes a tree out the way `du` does: one entry at a time, with
+/// everything in a directory before the directory itself, so the last one
+/// is the grand total. A top level is written as each of its trees in turn,
+/// without a total (also like `du`). Entries are written as they're
+/// reached, so nothing much is kept in memory whatever the size of the
+/// tree.
+pub struct Report {
+    format: ReportFormat,
+    max_depth: Option<usize>,
+    order: ReportOrder,
+    raw_sizes: bool,
+    threshold: u64,
+    all: bool,
+    size_mode: SizeMode,
+    names: NameDisplay,
+    top: Option<usize>,
+}
+
+impl Report {
+
+    pub fn new() -> Self {
+        Report {
+            format: ReportFormat::Text,
+            max_depth: None,
+            order: ReportOrder::Size,
+            raw_sizes: false,
+            threshold: 0,
+            all: false,
+            size_mode: SizeMode::Disk,
+            names: NameDisplay::Escape,
+            top: None,
+        }
+    }
+
+    pub fn format(mut self, format: ReportFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Only go this far below the root, which is at depth zero. Everything
+    /// deeper still counts towards the totals.
+    pub fn max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn order(mut self, order: ReportOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Show sizes as plain numbers of bytes, rather than rounded off with
+    /// units like the browser has them. Sizes are always in bytes in
+    /// formats other than `Text`.
+    pub fn raw_sizes(mut self, raw_sizes: bool) -> Self {
+        self.raw_sizes = raw_sizes;
+        self
+    }
+
+    /// Leave out anything smaller than `threshold` bytes.
+    pub fn threshold(mut self, threshold: u64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Report everything, not just directories.
+    pub fn all(mut self, all: bool) -> Self {
+        self.all = all;
+        self
+    }
+
+    pub fn size_mode(mut self, size_mode: SizeMode) -> Self {
+        self.size_mode = size_mode;
+        self
+    }
+
+    pub fn names(mut self, names: NameDisplay) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Instead of the whole tree, report just the `n` biggest files and the
+    /// `n` directories with the most directly in them, from anywhere in the
+    /// tree (see `Largest`). In `Text`, directories are shown with what's
+    /// directly in them, and each list has a heading.
+    pub fn top(mut self, n: Option<usize>) -> Self {
+        self.top = n;
+        self
+    }
+
+    pub fn write<W: Write>(&self, fst: &FSTree, out: W) -> io::Result<()> {
+        let mut out = BufWriter::new(out);
+
+        match self.format {
+            ReportFormat::Json => write!(out, "[")?,
+            ReportFormat::Csv => writeln!(out, "{}", FIELDS.join(","))?,
+            _ => (),
+        }
+
+        let mut written = 0;
+
+        match self.top {
+            Some(n) => self.write_top(&mut out, fst, n, &mut written)?,
+
+            None if fst.is_top_level() => for dir in fst.root().children() {
+                self.write_node(&mut out, dir, 0, &mut written)?;
+            },
+
+            None => { self.write_node(&mut out, fst.root(), 0, &mut written)?; }
+        }
+
+        if self.format == ReportFormat::Json {
+            writeln!(out, "\n]")?;
+        }
+
+        out.flush()
+    }
+
+    // write out `node` and whatever's in it that's wanted, returning how many
+    // entries there are inside it altogether. `written` counts the records
+    // written so far.
+    fn write_node<W: Write>(&self, out: &mut W, node: Node, depth: usize, written: &mut u64) -> io::Result<u64> {
+        let size = node.size().unwrap_or_default().get(self.size_mode);
+
+        // nothing inside something small can be any bigger, except behind a
+        // followed symlink, which isn't counted in here
+        if size < self.threshold {
+            self.write_linked(out, node, depth, written)?;
+            return Ok(self.count(node));
+        }
+
+        let mut count = 0;
+
+        if self.max_depth.map(|max_depth| depth < max_depth ).unwrap_or(true) {
+            let (mut shown, hidden): (Vec<Node>, Vec<Node>) = node.children()
+                .partition(|child| (self.all || child.has_contents()) && !child.is_excluded() );
+
+            match self.order {
+                ReportOrder::Size => shown.sort_by_key(|child|
+                    std::cmp::Reverse(child.size().unwrap_or_default().get(self.size_mode))
+                ),
+
+                ReportOrder::Name => (), // they're kept that way
+            }
+
+            for child in shown {
+                count += 1 + self.write_node(out, child, depth + 1, written)?;
+            }
+
+            for child in hidden {
+                count += 1 + self.count(child);
+            }
+        } else {
+            count = self.count(node);
+        }
+
+        self.write_record(out, node, size, count, written)?;
+        Ok(count)
+    }
+
+    // write whatever's behind followed symlinks somewhere inside `node`,
+    // which is too small to be written itself
+    fn write_linked<W: Write>(&self, out: &mut W, node: Node, depth: usize, written: &mut u64) -> io::Result<()> {
+        if !self.max_depth.map(|max_depth| depth < max_depth ).unwrap_or(true) {
+            return Ok(());
+        }
+
+        for child in node.children().filter(|child| child.has_contents() ) {
+            if child.is_linked() {
+                self.write_node(out, child, depth + 1, written)?;
+            } else {
+                self.write_linked(out, child, depth + 1, written)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_top<W: Write>(&self, out: &mut W, fst: &FSTree, n: usize, written: &mut u64) -> io::Result<()> {
+        let largest = Largest::find(fst, n, self.size_mode);
+
+        let wanted = |ranked: &&Ranked| ranked.size >= self.threshold;
+        let files: Vec<&Ranked> = largest.files.iter().filter(wanted).collect();
+        let dirs: Vec<&Ranked> = largest.dirs.iter().filter(wanted).collect();
+
+        let text = self.format == ReportFormat::Text;
+        let lists = [("largest files:", files), ("largest directories, by what's directly in them:", dirs)];
+
+        for (i, (heading, list)) in lists.iter().enumerate() {
+            if text {
+                if i > 0 {
+                    writeln!(out)?;
+                }
+
+                writeln!(out, "{}", heading)?;
+            }
+
+            for ranked in list {
+                // everything ranked was found in the tree a moment ago
+                let node = fst.entries(&ranked.names).unwrap();
+
+                self.write_record(out, node, ranked.size, self.count(node), written)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // how many entries there are inside `node`, which only needs working
+    // out if it's going to be written
+    fn count(&self, node: Node) -> u64 {
+        if self.format == ReportFormat::Text {
+            0
+        } else {
+            node.children().map(|child| 1 + self.count(child) ).sum()
+        }
+    }
+
+    // `written` is how many records have gone before this one, and goes up
+    // by one
+    fn write_record<W: Write>(&self, out: &mut W, node: Node, size: u64, count: u64, written: &mut u64) -> io::Result<()> {
+        let path = self.names.show(node.path());
+        let first = *written == 0;
+        *written += 1;
+
+        if self.format == ReportFormat::Text {
+            let size_str = if self.raw_sizes {
+                size.to_string()
+            } else {
+                UI::format_size(size)
+            };
+
+            return writeln!(out, "{}\t{}", size_str, path);
+        }
+
+        // a directory's own size is what's in it, which is its total
+        let whole_size = node.whole_size().filter(|_| !node.has_contents() );
+
+        let fields = [
+            Field::Text(path),
+            Field::Text(kind_name(node.kind()).to_string()),
+            Field::Number(whole_size.map(|size| size.apparent as i64 )),
+            Field::Number(whole_size.map(|size| size.disk as i64 )),
+            Field::Number(node.size().map(|size| size.get(self.size_mode) as i64 )),
+            Field::Number(Some(count as i64).filter(|_| node.has_contents() )),
+            Field::Number(node.mtime()),
+            Field::Number(node.owner().map(|owner| owner as i64 )),
+            node.target()
+                .map(|target| Field::Text(self.names.show(target)) )
+                .unwrap_or(Field::Null),
+        ];
+
+        if self.format == ReportFormat::Csv {
+            for (i, field) in fields.iter().enumerate() {
+                if i > 0 {
+                    write!(out, ",")?;
+                }
+
+                field.write_csv(out)?;
+            }
+
+            return writeln!(out);
+        }
+
+        // records in an array are separated by commas, which go before
+        // each one since there's no knowing which one will be the last
+        if self.format == ReportFormat::Json {
+            if !first {
+                write!(out, ",")?;
+            }
+
+            writeln!(out)?;
+        }
+
+        write!(out, "{{")?;
+        for (i, (name, field)) in FIELDS.iter().zip(&fields).enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+
+            write!(out, "\"{}\":", name)?;
+            field.write_json(out)?;
+        }
+        write!(out, "}}")?;
+
+        if self.format == ReportFormat::Ndjson {
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+}
+
+// one field of a record, which can be missing
+enum Field {
+    Null,
+    Number(Option<i64>),
+    Text(String),
+}
+
+impl Field {
+
+    fn write_json<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match *self {
+            Field::Null | Field::Number(None) => write!(out, "null"),
+            Field::Number(Some(n)) => write!(out, "{}", n),
+
+            Field::Text(ref text) => {
+                write!(out, "\"")?;
+
+                for c in text.chars() {
+                    match c {
+                        '"' => write!(out, "\\\"")?,
+                        '\\' => write!(out, "\\\\")?,
+                        c if c.is_control() => write!(out, "\\u{:04x}", c as u32)?,
+                        c => write!(out, "{}", c)?,
+                    }
+                }
+
+                write!(out, "\"")
+            }
+        }
+    }
+
+    // missing fields are left empty, and text is quoted if it has to be
+    fn write_csv<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match *self {
+            Field::Null | Field::Number(None) => Ok(()),
+            Field::Number(Some(n)) => write!(out, "{}", n),
+
+            Field::Text(ref text) if text.contains([',', '"', '\n', '\r']) =>
+                write!(out, "\"{}\"", text.replace('"', "\"\"")),
+
+            Field::Text(ref text) => write!(out, "{}", text),
+        }
+    }
+}
+
+fn kind_name(kind: EntryKind) -> &'static str {
+    match kind {
+        EntryKind::Dir => "dir",
+        EntryKind::File => "file",
+        EntryKind::Symlink => "symlink",
+        EntryKind::Linked => "followed_symlink",
+        EntryKind::Cycle => "symlink_cycle",
+        EntryKind::MountPoint => "mount_point",
+        EntryKind::Excluded => "excluded",
+        EntryKind::Special(SpecialKind::Fifo) => "fifo",
+        EntryKind::Special(SpecialKind::Socket) => "socket",
+        EntryKind::Special(SpecialKind::BlockDevice) => "block_device",
+        EntryKind::Special(SpecialKind::CharDevice) => "char_device",
+        EntryKind::Bad => "error",
+    }
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Read a size like `100M`, `1.5GiB` or `4096`. The units go up in powers
+/// of 1024, as in the browser, and a number on its own is bytes.
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let split = text.find(|c: char| !(c.is_ascii_digit() || c == '.') ).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+
+    let power = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 1,
+        "M" | "MB" | "MIB" => 2,
+        "G" | "GB" | "GIB" => 3,
+        "T" | "TB" | "TIB" => 4,
+        "P" | "PB" | "PIB" => 5,
+        _ => return Err(format!("{}: unknown size unit {:?}", text, unit.trim())),
+    };
+
+    number.parse::<f64>()
+        .map(|number| (number * 1024f64.powi(power)) as u64 )
+        .map_err(|_| format!("{}: not a size", text) )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::scan::Scanner;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("12B"), Ok(12));
+        assert_eq!(parse_size("1k"), Ok(1024));
+        assert_eq!(parse_size("100M"), Ok(100 << 20));
+        assert_eq!(parse_size("100 MB"), Ok(100 << 20));
+        assert_eq!(parse_size("1.5GiB"), Ok(3 << 29));
+        assert_eq!(parse_size(" 2T "), Ok(2 << 40));
+        assert_eq!(parse_size("1p"), Ok(1 << 50));
+    }
+
+    #[test]
+    fn not_sizes() {
+        for text in &["", "M", "lots", "10X", "10MiBs", "1.2.3K", "-5", "1e3"] {
+            assert!(parse_size(text).is_err(), "{:?}", text);
+        }
+    }
+
+    fn json(field: Field) -> String {
+        let mut out = Vec::new();
+        field.write_json(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn csv(field: Field) -> String {
+        let mut out = Vec::new();
+        field.write_csv(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn text(text: &str) -> Field {
+        Field::Text(text.to_string())
+    }
+
+    #[test]
+    fn json_fields() {
+        assert_eq!(json(Field::Null), "null");
+        assert_eq!(json(Field::Number(None)), "null");
+        assert_eq!(json(Field::Number(Some(-5))), "-5");
+        assert_eq!(json(text("plain.txt")), "\"plain.txt\"");
+        assert_eq!(json(text("say \"hi\"")), "\"say \\\"hi\\\"\"");
+        assert_eq!(json(text("C:\\dir")), "\"C:\\\\dir\"");
+        assert_eq!(json(text("new\nline\ttab\u{1b}")), "\"new\\u000aline\\u0009tab\\u001b\"");
+        assert_eq!(json(text("café ☃")), "\"café ☃\"");
+    }
+
+    #[test]
+    fn csv_fields() {
+        assert_eq!(csv(Field::Null), "");
+        assert_eq!(csv(Field::Number(None)), "");
+        assert_eq!(csv(Field::Number(Some(42))), "42");
+        assert_eq!(csv(text("plain.txt")), "plain.txt");
+        assert_eq!(csv(text("a,b")), "\"a,b\"");
+        assert_eq!(csv(text("say \"hi\"")), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv(text("two\nlines")), "\"two\nlines\"");
+        assert_eq!(csv(text("carriage\rreturn")), "\"carriage\rreturn\"");
+    }
+
+    #[test]
+    fn linked_below_the_threshold() {
+        let tmp = TempDir::new("dredge").unwrap();
+        let root = tmp.path().join("root");
+        fs::create_dir_all(root.join("small")).unwrap();
+        fs::create_dir_all(tmp.path().join("outside")).unwrap();
+        fs::write(tmp.path().join("outside/big"), vec![1u8; 100_000]).unwrap();
+        std::os::unix::fs::symlink("../../outside", root.join("small/link")).unwrap();
+
+        // neither the root nor `small` counts what's behind the link
+        let fst = Scanner::new().follow_symlinks(true).scan(&root).unwrap();
+        let text = |max_depth| {
+            let mut out = Vec::new();
+            Report::new()
+                .size_mode(SizeMode::Apparent)
+                .raw_sizes(true)
+                .max_depth(max_depth)
+                .threshold(50_000)
+                .write(&fst, &mut out)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let report = text(None);
+        assert_eq!(report.lines().count(), 1, "{}", report);
+        assert!(report.starts_with("100000\t") && report.ends_with("small/link\n"), "{}", report);
+
+        assert_eq!(text(Some(1)), "");
+    }
+
+    #[test]
+    fn json_with_roots_left_out() {
+        let tmp = TempDir::new("dredge").unwrap();
+
+        let mut roots = Vec::new();
+        for &(name, size) in &[("big", 100_000), ("small", 1), ("bigger", 200_000), ("smaller", 0)] {
+            let root = tmp.path().join(name);
+            fs::create_dir_all(&root).unwrap();
+            fs::write(root.join("file"), vec![1u8; size]).unwrap();
+            roots.push(root);
+        }
+
+        let fst = Scanner::new().scan_all(&roots).unwrap();
+        let json = |threshold| {
+            let mut out = Vec::new();
+            Report::new()
+                .format(ReportFormat::Json)
+                .size_mode(SizeMode::Apparent)
+                .max_depth(Some(0))
+                .threshold(threshold)
+                .write(&fst, &mut out)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        // whichever roots are left out, the commas only go between records
+        let some = json(50_000);
+        assert!(some.starts_with("[\n{") && some.ends_with("}\n]\n"), "{}", some);
+        assert_eq!(some.matches("\n{").count(), 2, "{}", some);
+        assert_eq!(some.matches("},\n{").count(), 1, "{}", some);
+
+        assert_eq!(json(1_000_000), "[\n]\n");
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright (C) 2016  Michael Dunsmuir
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Size limits on parts of a tree, for checking that nothing's got out of
+// hand.
+
+extern crate std;
+
+use std::path::{Path, PathBuf};
+
+use super::fstree::{FSTree, Node, SizeMode};
+use super::pattern::Pattern;
+use super::report::parse_size;
+
+/// A limit on how big some of the entries in a tree can be.
+///
+/// Rules are written as `<pattern> > <size>`, e.g. `logs/*.log > 100M`.
+/// Patterns are globs, as for `--exclude`, but a pattern with a `/` in it
+/// (other than on the end) is matched against the path from the top of the
+/// tree rather than the whole path, so `logs/*.log` only means the `logs`
+/// directory at the top. One without matches names anywhere. A `/` on the
+/// end means only directories match, and `/` on its own means the top of
+/// the tree.
+pub struct Rule {
+    // `None` for the top of the tree
+    pattern: Option<Pattern>,
+    anchored: bool,
+    dirs_only: bool,
+    limit: u64,
+
+    // what the rule looked like, if it was written down somewhere
+    source: Option<String>,
+}
+
+/// An entry that's bigger than some rule says it should be.
+pub struct Violation {
+    pub path: PathBuf,
+    pub size: u64,
+    pub limit: u64,
+
+    /// The rule that was broken, as it was written, if it was.
+    pub rule: Option<String>,
+}
+
+impl Rule {
+
+    /// A limit on the size of the whole tree.
+    pub fn root(limit: u64) -> Self {
+        Rule {
+            pattern: None,
+            anchored: true,
+            dirs_only: true,
+            limit,
+            source: None,
+        }
+    }
+
+    pub fn new(source: &str) -> Result<Self, String> {
+        let (pattern, limit) = match source.rfind('>') {
+            Some(at) => (source[..at].trim(), source[at + 1..].trim()),
+            None => return Err(format!("'{}': expected <pattern> > <size>", source)),
+        };
+
+        let limit = parse_size(limit)?;
+        let dirs_only = pattern.ends_with('/');
+
+        // paths are matched from the top of the tree, so a slash at the
+        // start only says the pattern's anchored there
+        let pattern = pattern.trim_end_matches('/');
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+
+        let pattern = if pattern.is_empty() && dirs_only {
+            None
+        } else if pattern.is_empty() {
+            return Err(format!("'{}': no pattern", source));
+        } else {
+            Some(Pattern::new(pattern)?)
+        };
+
+        Ok(Rule {
+            pattern,
+            anchored,
+            dirs_only,
+            limit,
+            source: Some(source.to_string()),
+        })
+    }
+
+    /// Read rules from a rules file, one to a line. Blank lines and lines
+    /// starting with `#` are ignored.
+    pub fn parse_file(text: &str) -> Result<Vec<Self>, String> {
+        text.lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()) )
+            .filter(|&(_, line)| !line.is_empty() && !line.starts_with('#') )
+            .map(|(number, line)| Self::new(line).map_err(|e| format!("line {}: {}", number, e) ) )
+            .collect()
+    }
+
+    // whether this rule is about `node`, which is at `relative` below the
+    // top of the tree
+    fn applies(&self, node: Node, relative: &Path) -> bool {
+        if self.dirs_only && !node.has_contents() {
+            return false;
+        }
+
+        match self.pattern {
+            None => relative.as_os_str().is_empty(),
+            Some(_) if relative.as_os_str().is_empty() => false,
+            Some(ref pattern) if self.anchored => pattern.matches(relative.as_os_str()),
+            Some(ref pattern) => pattern.matches(node.name()),
+        }
+    }
+}
+
+/// Check everything in `fst` against `rules`, going by `size_mode`, and
+/// return whatever breaks them, biggest first. An entry can break more than
+/// one rule. Each of the trees in a top level is checked as a tree of its
+/// own. Anything that couldn't be read is skipped, so it's up to the caller
+/// to say so (see `Node::errors_list`).
+pub fn check(fst: &FSTree, rules: &[Rule], size_mode: SizeMode) -> Vec<Violation> {
+    let mut violations = Vec::new();
+
+    if fst.is_top_level() {
+        for dir in fst.root().children() {
+            check_node(dir, &mut PathBuf::new(), rules, size_mode, &mut violations);
+        }
+    } else {
+        check_node(fst.root(), &mut PathBuf::new(), rules, size_mode, &mut violations);
+    }
+
+    violations.sort_by_key(|violation| std::cmp::Reverse(violation.size) );
+    violations
+}
+
+fn check_node(
+    node: Node,
+    relative: &mut PathBuf,
+    rules: &[Rule],
+    size_mode: SizeMode,
+    violations: &mut Vec<Violation>,
+) {
+    // nothing that couldn't be read (or wasn't) has a size to go over
+    let size = match node.size() {
+        Some(size) => size.get(size_mode),
+        None => return,
+    };
+
+    for rule in rules {
+        if size > rule.limit && rule.applies(node, relative) {
+            violations.push(Violation {
+                path: node.path(),
+                size,
+                limit: rule.limit,
+                rule: rule.source.clone(),
+            });
+        }
+    }
+
+    for child in node.children() {
+        relative.push(child.name());
+        check_node(child, relative, rules, size_mode, violations);
+        relative.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::scan::Scanner;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn parsing() {
+        let rule = Rule::new("logs/*.log > 100M").unwrap();
+        assert!(rule.pattern.is_some() && rule.anchored && !rule.dirs_only);
+        assert_eq!(rule.limit, 100 << 20);
+        assert_eq!(rule.source.as_deref(), Some("logs/*.log > 100M"));
+
+        let rule = Rule::new("target/>5G").unwrap();
+        assert!(rule.pattern.is_some() && !rule.anchored && rule.dirs_only);
+        assert_eq!(rule.limit, 5 << 30);
+
+        let rule = Rule::new("/target/ > 1K").unwrap();
+        assert!(rule.anchored && rule.dirs_only);
+
+        let rule = Rule::new("/ > 10G").unwrap();
+        assert!(rule.pattern.is_none() && rule.dirs_only);
+
+        // only the last `>` splits the rule
+        let rule = Rule::new("a>b > 1").unwrap();
+        assert_eq!(rule.limit, 1);
+    }
+
+    #[test]
+    fn bad_rules() {
+        for source in &["logs", "logs > ", "> 100M", "logs > lots", "[logs > 1M"] {
+            assert!(Rule::new(source).is_err(), "{:?}", source);
+        }
+
+        let err = Rule::parse_file("# limits\n\n*.iso > 4G\nwhatever\n").err().unwrap();
+        assert!(err.starts_with("line 4: "), "{}", err);
+        assert_eq!(Rule::parse_file("# limits\n\n  *.iso > 4G  \n").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn checking() {
+        let tmp = TempDir::new("dredge").unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("logs/old")).unwrap();
+        fs::write(dir.join("logs/big.log"), vec![1u8; 100_000]).unwrap();
+        fs::write(dir.join("logs/old/big.log"), vec![1u8; 100_000]).unwrap();
+
+        let fst = Scanner::new().scan(dir).unwrap();
+        let check = |rules: &[&str]| {
+            let rules: Vec<Rule> = rules.iter().map(|rule| Rule::new(rule).unwrap() ).collect();
+            check(&fst, &rules, SizeMode::Apparent)
+        };
+
+        assert_eq!(check(&["*.log > 50K"]).len(), 2);
+        assert_eq!(check(&["logs/*.log > 50K"]).len(), 1);
+        assert_eq!(check(&["*.log/ > 50K"]).len(), 0);
+        assert_eq!(check(&["logs/ > 150K"]).len(), 1);
+        assert_eq!(check(&["/ > 1M", "old > 1M"]).len(), 0);
+
+        let violations = check(&["/ > 150K"]);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].path, dir);
+        assert_eq!(violations[0].limit, 150 << 10);
+    }
+}
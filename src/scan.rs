@@ -0,0 +1,899 @@
+// Copyright (C) 2016  Michael Dunsmuir
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate std;
+
+use std::collections::{HashSet, VecDeque};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use std::fs;
+use std::io;
+
+use super::fstree::{Contents, Entry, EntryKind, FSTree, LinkAttribution, Size};
+use super::os;
+use super::pattern::Filter;
+
+/// How long `Scan::poll` may spend grafting before it hands control back, so
+/// that a fast scan can't starve the UI.
+const POLL_BUDGET: Duration = Duration::from_millis(50);
+
+/// A directory waiting to be read. `names` is where it lives in the tree,
+/// relative to the root. `linked` is set if we got here by following a
+/// symlink, and `ancestors` identifies the directories on the way (only
+/// when following symlinks; otherwise there can't be any cycles).
+/// `options` are the ones for whichever directory the scan started from,
+/// since there can be more than one.
+struct Job {
+    names: Vec<OsString>,
+    path: PathBuf,
+    linked: bool,
+    ancestors: Vec<(u64, u64)>,
+    options: Arc<ScanOptions>,
+}
+
+/// What a worker sends back once it has read a directory, or why it
+/// couldn't.
+struct Batch {
+    names: Vec<OsString>,
+    path: PathBuf,
+    linked: bool,
+    contents: Result<Contents, io::ErrorKind>,
+}
+
+/// How far a scan has got so far.
+#[derive(Clone, Default)]
+pub struct Progress {
+    pub files: u64,
+    pub dirs: u64,
+    pub bytes: Size,
+    pub errors: u64,
+
+    /// The directory most recently read.
+    pub current: PathBuf,
+}
+
+impl Progress {
+
+    fn add(&mut self, batch: &Batch) {
+        self.dirs += 1;
+        self.current = batch.path.clone();
+
+        match batch.contents {
+            Ok(ref contents) => {
+                // bytes behind followed symlinks don't count in the end
+                if !batch.linked {
+                    self.bytes += contents.size();
+                }
+
+                // symlinks, mount points and the like are neither
+                for entry in contents.iter() {
+                    match entry.kind() {
+                        EntryKind::File | EntryKind::Special(_) => self.files += 1,
+                        EntryKind::Bad => self.errors += 1,
+                        _ => (),
+                    }
+                }
+            }
+
+            Err(_) => self.errors += 1,
+        }
+    }
+}
+
+type ProgressCallback<'a> = Box<dyn FnMut(&Progress) + 'a>;
+
+/// The shared job queue. The flag is set once the whole tree has been read
+/// so that idle workers know to go home.
+struct Queue {
+    jobs: Mutex<(VecDeque<Job>, bool)>,
+    ready: Condvar,
+}
+
+impl Queue {
+
+    fn push(&self, jobs: Vec<Job>) {
+        if !jobs.is_empty() {
+            self.jobs.lock().unwrap().0.extend(jobs);
+            self.ready.notify_all();
+        }
+    }
+
+    fn pop(&self) -> Option<Job> {
+        let mut guard = self.jobs.lock().unwrap();
+
+        loop {
+            if let Some(job) = guard.0.pop_front() {
+                return Some(job);
+            } else if guard.1 {
+                return None;
+            }
+
+            guard = self.ready.wait(guard).unwrap();
+        }
+    }
+
+    fn finish(&self) {
+        self.jobs.lock().unwrap().1 = true;
+        self.ready.notify_all();
+    }
+}
+
+/// The settings that decide what each directory read turns up.
+#[derive(Default)]
+pub struct ScanOptions {
+    /// The directory the scan started from, which patterns with a `/` in
+    /// them are matched from.
+    pub root: PathBuf,
+
+    /// If set, directories on any other device are recorded as mount points
+    /// rather than descended into.
+    pub device: Option<u64>,
+
+    pub filter: Filter,
+
+    /// Whether excluded entries are kept in the tree (as `Excluded`) or just
+    /// left out.
+    pub show_excluded: bool,
+
+    /// Whether symlinks to directories are followed (as `Linked`) rather
+    /// than left as they are.
+    pub follow_symlinks: bool,
+}
+
+/// Reads a directory tree into an `FSTree` using a pool of worker threads.
+///
+/// Each worker reads one directory level at a time and queues up any
+/// subdirectories it finds, so the scan fans out as the tree gets wider.
+/// Only the thread holding the `Scan` touches the tree itself: it grafts
+/// each directory's contents in as they arrive, which keeps the
+/// totals exactly the same as a serial scan would produce.
+pub struct Scanner<'a> {
+    threads: usize,
+    one_file_system: bool,
+    follow_symlinks: bool,
+    filter: Filter,
+    show_excluded: bool,
+    attribution: LinkAttribution,
+    on_progress: Option<(Duration, ProgressCallback<'a>)>,
+}
+
+impl<'a> Scanner<'a> {
+
+    pub fn new() -> Self {
+        Scanner {
+            threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            one_file_system: false,
+            follow_symlinks: false,
+            filter: Filter::default(),
+            show_excluded: false,
+            attribution: LinkAttribution::First,
+            on_progress: None,
+        }
+    }
+
+    /// Stay on the root directory's filesystem, and don't descend into
+    /// anything mounted underneath it.
+    pub fn one_file_system(mut self, one_file_system: bool) -> Self {
+        self.one_file_system = one_file_system;
+        self
+    }
+
+    /// Descend into symlinks that lead to directories. What's inside them is
+    /// totalled up separately, not counted towards the directories above,
+    /// and links that lead back into a directory they're already in are
+    /// left alone.
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Skip entries that `filter` excludes. If `show_excluded` is set they
+    /// still show up in the tree, just without any contents or size.
+    pub fn filter(mut self, filter: Filter, show_excluded: bool) -> Self {
+        self.filter = filter;
+        self.show_excluded = show_excluded;
+        self
+    }
+
+    /// Choose which paths a file with several hard links is counted
+    /// against. Either way it's only counted once in total.
+    pub fn hard_links(mut self, attribution: LinkAttribution) -> Self {
+        self.attribution = attribution;
+        self
+    }
+
+    /// Set the number of worker threads. Zero is treated as one.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = std::cmp::max(1, threads);
+        self
+    }
+
+    /// Have `callback` called with the scan's progress every `interval`,
+    /// and once more when the scan is done. It's called from whichever
+    /// thread is polling or finishing the `Scan`.
+    pub fn on_progress<F>(mut self, interval: Duration, callback: F) -> Self
+        where F: FnMut(&Progress) + 'a
+    {
+        self.on_progress = Some((interval, Box::new(callback)));
+        self
+    }
+
+    /// Scan the whole tree, blocking until it's done. Returns `None` if the
+    /// root directory couldn't be read.
+    pub fn scan<P: AsRef<Path>>(self, path: P) -> Option<FSTree> {
+        let (mut fst, scan) = self.start(path);
+
+        if scan.finish(&mut fst) {
+            Some(fst)
+        } else {
+            None
+        }
+    }
+
+    /// Like `scan`, but for any number of directories. One on its own is
+    /// scanned just the same, and several go under a top level of their own
+    /// (see `FSTree::top_level`), in which case there's always a tree.
+    pub fn scan_all(self, paths: &[PathBuf]) -> Option<FSTree> {
+        let (mut fst, scan) = self.start_all(paths);
+
+        if scan.finish(&mut fst) {
+            Some(fst)
+        } else {
+            None
+        }
+    }
+
+    /// Start scanning in the background. The returned tree starts out empty
+    /// and only fills up as the `Scan` is polled.
+    pub fn start<P: AsRef<Path>>(self, path: P) -> (FSTree, Scan<'a>) {
+        let path = path.as_ref().to_path_buf();
+        let job = self.root_job(Vec::new(), path.clone());
+
+        self.start_jobs(FSTree::new(path), vec![job])
+    }
+
+    /// Like `start`, but for any number of directories, as with `scan_all`.
+    /// Files with several hard links are only counted once between all of
+    /// them, but a directory that's inside another one is counted twice.
+    pub fn start_all(self, paths: &[PathBuf]) -> (FSTree, Scan<'a>) {
+        if let [ref path] = *paths {
+            return self.start(path);
+        }
+
+        let jobs = paths.iter()
+            .map(|path| self.root_job(vec![path.as_os_str().to_os_string()], path.clone()) )
+            .collect();
+
+        self.start_jobs(FSTree::top_level(paths), jobs)
+    }
+
+    // the job for reading the directory a scan starts from, which is at
+    // `names` in the tree
+    fn root_job(&self, names: Vec<OsString>, path: PathBuf) -> Job {
+        // the root counts as an ancestor of everything, for spotting cycles
+        let ancestors = if self.follow_symlinks {
+            fs::metadata(&path).ok().map(|md| os::inode(&md) ).into_iter().collect()
+        } else {
+            Vec::new()
+        };
+
+        Job {
+            names,
+            options: Arc::new(self.options(&path)),
+            path,
+            linked: false,
+            ancestors,
+        }
+    }
+
+    fn start_jobs(self, fst: FSTree, jobs: Vec<Job>) -> (FSTree, Scan<'a>) {
+        let queue = Arc::new(Queue {
+            jobs: Mutex::new((VecDeque::new(), false)),
+            ready: Condvar::new(),
+        });
+
+        let outstanding = jobs.len();
+        queue.push(jobs);
+
+        let (sender, receiver) = mpsc::channel();
+        let workers = (0..self.threads).map(|_| {
+            let queue = queue.clone();
+            let sender = sender.clone();
+            thread::spawn(move || work(&queue, &sender) )
+        }).collect();
+
+        let scan = Scan {
+            queue,
+            receiver,
+            workers,
+            outstanding,
+            root_ok: true,
+            attribution: self.attribution,
+            inodes: HashSet::new(),
+            progress: Progress::default(),
+            on_progress: self.on_progress,
+            last_report: Instant::now(),
+        };
+
+        (fst, scan)
+    }
+
+    /// Read just the entry at `path`, somewhere inside the tree rooted at
+    /// `root`, the way a scan of the whole tree would have. If it's a
+    /// directory, everything inside it is read too. Returns `None` if it
+    /// would have been left out, or isn't there at all.
+    ///
+    /// This is for the odd entry that changes, which is usually small, so
+    /// it's all read on this thread rather than by a pool of workers, and
+    /// the same scanner can be used again and again.
+    pub fn scan_entry<P: AsRef<Path>, Q: AsRef<Path>>(&self, root: P, path: Q) -> Option<FSTree> {
+        let path = path.as_ref();
+        let options = self.options(root.as_ref());
+
+        if let Err(e) = fs::symlink_metadata(path) {
+            if e.kind() == io::ErrorKind::NotFound {
+                return None;
+            }
+        }
+
+        let (mut ancestors, linked) = self.ancestors(root.as_ref(), path);
+
+        Entry::read(path, &options, &ancestors).map(|mut entry| {
+            // a file with several links gets the share it would have in a
+            // scan of its own; `FSTree::insert_entry` sorts out which link
+            // counts once it's in the tree
+            if !linked {
+                entry.share_links(self.attribution, &mut HashSet::new());
+            }
+
+            let (has_contents, is_linked, entry_inode) = (entry.has_contents(), entry.is_linked(), entry.inode());
+            let mut fst = FSTree::from_entry(path.to_path_buf(), entry);
+
+            if has_contents {
+                // read what's inside as if the scan had got here from the
+                // root, so patterns still match from there and symlinks
+                // back up the tree are still cycles
+                if self.follow_symlinks {
+                    ancestors.push(entry_inode);
+                }
+
+                let job = Job {
+                    names: Vec::new(),
+                    path: path.to_path_buf(),
+                    linked: linked || is_linked,
+                    ancestors,
+                    options: Arc::new(options),
+                };
+
+                match self.read_serially(path, job) {
+                    Some(scanned) => fst.fill(scanned),
+
+                    None => {
+                        let error = fs::read_dir(path).err()
+                            .map(|e| e.kind() )
+                            .unwrap_or(io::ErrorKind::Other);
+                        fst.graft(&[], Err(error));
+                    }
+                }
+            }
+
+            fst
+        })
+    }
+
+    // read the directory at `path` and everything in it, starting with
+    // `job`, without any worker threads. Returns `None` if the directory
+    // couldn't be read.
+    fn read_serially(&self, path: &Path, job: Job) -> Option<FSTree> {
+        let mut fst = FSTree::new(path);
+
+        // nothing ever arrives from the queue or the channel; the batches
+        // are handed straight to `apply` instead
+        let mut scan = Scan {
+            queue: Arc::new(Queue {
+                jobs: Mutex::new((VecDeque::new(), true)),
+                ready: Condvar::new(),
+            }),
+            receiver: mpsc::channel().1,
+            workers: Vec::new(),
+            outstanding: 1,
+            root_ok: true,
+            attribution: self.attribution,
+            inodes: HashSet::new(),
+            progress: Progress::default(),
+            on_progress: None,
+            last_report: Instant::now(),
+        };
+
+        let mut jobs = vec![job];
+        while let Some(job) = jobs.pop() {
+            let (batch, subdirs) = read(job);
+            scan.apply(&mut fst, batch);
+            jobs.extend(subdirs);
+        }
+
+        if scan.root_ok { Some(fst) } else { None }
+    }
+
+    // the (device, inode) pairs of the directories from `root` down to the
+    // one `path` is in, when following symlinks, and whether any of them
+    // was reached through one
+    fn ancestors(&self, root: &Path, path: &Path) -> (Vec<(u64, u64)>, bool) {
+        if !self.follow_symlinks {
+            return (Vec::new(), false);
+        }
+
+        let mut dir = root.to_path_buf();
+        let mut ancestors: Vec<_> = fs::metadata(&dir).ok().map(|md| os::inode(&md) ).into_iter().collect();
+        let mut linked = false;
+
+        let between = path.parent()
+            .and_then(|parent| parent.strip_prefix(root).ok() )
+            .unwrap_or_else(|| Path::new("") );
+
+        for name in between {
+            dir.push(name);
+            linked |= fs::symlink_metadata(&dir).map(|md| md.file_type().is_symlink() ).unwrap_or(false);
+            ancestors.extend(fs::metadata(&dir).ok().map(|md| os::inode(&md) ));
+        }
+
+        (ancestors, linked)
+    }
+
+    fn options(&self, root: &Path) -> ScanOptions {
+        ScanOptions {
+            root: root.to_path_buf(),
+
+            device: if self.one_file_system {
+                fs::metadata(root).ok().map(|md| os::device(&md) )
+            } else {
+                None
+            },
+
+            filter: self.filter.clone(),
+            show_excluded: self.show_excluded,
+            follow_symlinks: self.follow_symlinks,
+        }
+    }
+}
+
+/// Something that makes scanners set up the same way as the one that read
+/// the tree in the first place, for reading whatever turns up later.
+pub type ScannerFactory<'a> = dyn Fn() -> Scanner<'a> + 'a;
+
+impl<'a> Default for Scanner<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A scan in progress. The tree it was started with must be passed back in
+/// every time it's polled.
+pub struct Scan<'a> {
+    queue: Arc<Queue>,
+    receiver: mpsc::Receiver<Batch>,
+    workers: Vec<thread::JoinHandle<()>>,
+    outstanding: usize,
+    root_ok: bool,
+    attribution: LinkAttribution,
+    inodes: HashSet<(u64, u64)>,
+    progress: Progress,
+    on_progress: Option<(Duration, ProgressCallback<'a>)>,
+    last_report: Instant,
+}
+
+impl<'a> Scan<'a> {
+
+    pub fn is_done(&self) -> bool {
+        self.outstanding == 0
+    }
+
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    /// Graft in whatever has arrived since the last poll without waiting
+    /// for anything else. Returns `true` if the tree changed.
+    pub fn poll(&mut self, fst: &mut FSTree) -> bool {
+        let started = Instant::now();
+        let mut changed = false;
+
+        while !self.is_done() && started.elapsed() < POLL_BUDGET {
+            match self.receiver.try_recv() {
+                Ok(batch) => {
+                    self.apply(fst, batch);
+                    changed = true;
+                }
+
+                Err(_) => break,
+            }
+        }
+
+        self.report(self.is_done());
+        changed
+    }
+
+    /// Wait for the rest of the tree. Returns `false` if the root directory
+    /// couldn't be read.
+    pub fn finish(mut self, fst: &mut FSTree) -> bool {
+        // don't block for longer than the progress interval, so that the
+        // callback keeps getting called even when nothing is arriving
+        let timeout = self.on_progress.as_ref()
+            .map(|&(interval, _)| interval )
+            .unwrap_or(Duration::from_secs(1));
+
+        while !self.is_done() {
+            if let Ok(batch) = self.receiver.recv_timeout(timeout) {
+                self.apply(fst, batch);
+            }
+
+            self.report(false);
+        }
+
+        self.report(true);
+
+        self.queue.finish();
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+
+        self.root_ok
+    }
+
+    // every batch finishes one directory and may start some more, so we're
+    // done once the number of outstanding directories hits zero
+    fn apply(&mut self, fst: &mut FSTree, mut batch: Batch) {
+        self.outstanding -= 1;
+
+        // batches are applied one at a time, in order, so this is the one
+        // place where "the first link we saw" is well defined. Files behind
+        // followed symlinks don't count, so they don't get a say.
+        match batch.contents {
+            Ok(ref mut contents) if !batch.linked => for entry in contents.iter_mut() {
+                entry.share_links(self.attribution, &mut self.inodes);
+            },
+
+            _ => (),
+        }
+
+        self.progress.add(&batch);
+
+        match batch.contents {
+            Ok(ref contents) => self.outstanding += contents.subdirs().len(),
+
+            Err(_) if batch.names.is_empty() => {
+                // an empty graft still clears the root's pending count
+                self.root_ok = false;
+                fst.graft(&batch.names, Ok(Contents::new()));
+                return;
+            }
+
+            Err(_) => (),
+        }
+
+        fst.graft(&batch.names, batch.contents);
+
+        if self.is_done() {
+            fst.shrink_to_fit();
+        }
+    }
+
+    fn report(&mut self, force: bool) {
+        if let Some((interval, ref mut callback)) = self.on_progress {
+            if force || self.last_report.elapsed() >= interval {
+                callback(&self.progress);
+                self.last_report = Instant::now();
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Scan<'a> {
+    fn drop(&mut self) {
+        // don't wait around for the workers, just tell them to stop
+        self.queue.finish();
+    }
+}
+
+fn work(queue: &Queue, sender: &mpsc::Sender<Batch>) {
+    while let Some(job) = queue.pop() {
+        let (batch, subdirs) = read(job);
+
+        // the batch has to go out before its subdirectories are queued,
+        // otherwise their batches could beat it to the tree
+        if sender.send(batch).is_err() {
+            return;
+        }
+
+        queue.push(subdirs);
+    }
+}
+
+// read the directory `job` is for, returning what's in it and the jobs for
+// the directories inside it
+fn read(job: Job) -> (Batch, Vec<Job>) {
+    let options = &job.options;
+    let contents = Contents::from_path(&job.path, options, &job.ancestors)
+        .map_err(|e| e.kind() );
+
+    let subdirs: Vec<Job> = contents.as_ref().map(|cs|
+        cs.subdirs().into_iter().map(|entry| {
+            let mut names = job.names.clone();
+            names.push(entry.name().to_os_string());
+
+            let mut ancestors = job.ancestors.clone();
+            if options.follow_symlinks {
+                ancestors.push(entry.inode());
+            }
+
+            Job {
+                names,
+                path: job.path.join(entry.name()),
+                linked: job.linked || entry.is_linked(),
+                ancestors,
+                options: options.clone(),
+            }
+        }).collect()
+    ).unwrap_or_default();
+
+    let batch = Batch {
+        names: job.names,
+        path: job.path,
+        linked: job.linked,
+        contents,
+    };
+
+    (batch, subdirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::fstree::{EntryKind, Node, Share};
+    use super::super::os;
+    use super::super::pattern::Pattern;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tempdir::TempDir;
+
+    // everything below `node`, one entry per line, by path from the top
+    fn flatten(node: Node, path: &Path, lines: &mut Vec<(PathBuf, Option<Size>, EntryKind)>) {
+        for child in node.children() {
+            let path = path.join(child.name());
+            lines.push((path.clone(), child.size(), child.kind()));
+            flatten(child, &path, lines);
+        }
+    }
+
+    #[test]
+    fn same_tree_as_one_thread() {
+        let tmp = TempDir::new("dredge").unwrap();
+        let dir = tmp.path();
+
+        fs::create_dir_all(dir.join("a/b/c")).unwrap();
+        fs::write(dir.join("a/b/c/deep"), vec![1u8; 10_000]).unwrap();
+        fs::write(dir.join("a/file"), vec![1u8; 50_000]).unwrap();
+        fs::hard_link(dir.join("a/file"), dir.join("a/b/link")).unwrap();
+
+        for i in 0..20 {
+            let sub = dir.join(format!("many/{}/{}", i, i * 2));
+            fs::create_dir_all(&sub).unwrap();
+            for j in 0..5 {
+                fs::write(sub.join(format!("file{}", j)), vec![1u8; 1000 * j]).unwrap();
+            }
+        }
+
+        // can't be read, unless the tests are running as root
+        fs::create_dir_all(dir.join("locked/inside")).unwrap();
+        fs::set_permissions(dir.join("locked"), fs::Permissions::from_mode(0o000)).unwrap();
+
+        // which link gets counted first depends on which thread gets there
+        // first, so go by even shares
+        let scanner = || Scanner::new().hard_links(LinkAttribution::Split);
+        let serial = scanner().threads(1).scan(dir).unwrap();
+        let parallel = scanner().threads(8).scan(dir).unwrap();
+        fs::set_permissions(dir.join("locked"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        let (mut serial_lines, mut parallel_lines) = (Vec::new(), Vec::new());
+        flatten(serial.root(), Path::new(""), &mut serial_lines);
+        flatten(parallel.root(), Path::new(""), &mut parallel_lines);
+
+        assert!(serial_lines.len() > 140);
+        assert_eq!(serial_lines, parallel_lines);
+        assert_eq!(serial.root().size(), parallel.root().size());
+    }
+
+    // a file with three links, two of them in another directory, and a
+    // plain file next to the third; returns the sizes of the two files
+    fn linked_files(dir: &Path) -> (u64, u64) {
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::write(dir.join("a/file"), vec![1u8; 3 * 4096]).unwrap();
+        fs::hard_link(dir.join("a/file"), dir.join("b/link")).unwrap();
+        fs::hard_link(dir.join("a/file"), dir.join("b/other_link")).unwrap();
+        fs::write(dir.join("a/plain"), vec![1u8; 4096]).unwrap();
+
+        let size = |path| os::size(&fs::metadata(dir.join(path)).unwrap());
+        (size("a/file"), size("a/plain"))
+    }
+
+    fn size_at(fst: &FSTree, path: &str) -> u64 {
+        let names: Vec<OsString> = path.split('/').map(OsString::from).collect();
+        fst.entries(&names).unwrap().size().unwrap().disk
+    }
+
+    #[test]
+    fn hard_links_first() {
+        let tmp = TempDir::new("dredge").unwrap();
+        let (linked, plain) = linked_files(tmp.path());
+
+        let fst = Scanner::new().hard_links(LinkAttribution::First).scan(tmp.path()).unwrap();
+        assert_eq!(fst.root().size().map(|size| size.disk ), Some(linked + plain));
+        assert_eq!(size_at(&fst, "a") + size_at(&fst, "b"), linked + plain);
+
+        // whichever link was found first has all of it
+        let mut links = vec![size_at(&fst, "a/file"), size_at(&fst, "b/link"), size_at(&fst, "b/other_link")];
+        links.sort();
+        assert_eq!(links, vec![0, 0, linked]);
+    }
+
+    #[test]
+    fn hard_links_split() {
+        let tmp = TempDir::new("dredge").unwrap();
+        let (linked, plain) = linked_files(tmp.path());
+
+        let fst = Scanner::new().hard_links(LinkAttribution::Split).scan(tmp.path()).unwrap();
+        assert_eq!(size_at(&fst, "a/file"), linked / 3);
+        assert_eq!(size_at(&fst, "b/link"), linked / 3);
+        assert_eq!(size_at(&fst, "a"), linked / 3 + plain);
+        assert_eq!(size_at(&fst, "b"), 2 * (linked / 3));
+        assert_eq!(fst.root().size().map(|size| size.disk ), Some(3 * (linked / 3) + plain));
+    }
+
+    #[test]
+    fn rescan_counts_hard_links_once() {
+        for &attribution in &[LinkAttribution::First, LinkAttribution::Split] {
+            let tmp = TempDir::new("dredge").unwrap();
+            let dir = tmp.path();
+            fs::create_dir_all(dir.join("a/sub")).unwrap();
+            fs::write(dir.join("a/file"), vec![1u8; 100_000]).unwrap();
+            fs::hard_link(dir.join("a/file"), dir.join("a/sub/link")).unwrap();
+
+            let mut fst = Scanner::new().hard_links(attribution).scan(dir).unwrap();
+            let before = fst.root().size();
+
+            // nothing's changed on disk, so nothing should change in the tree,
+            // however many times the same scanner reads it again
+            let scanner = Scanner::new().hard_links(attribution);
+            for names in &[&["a", "sub"][..], &["a", "sub", "link"], &["a", "file"], &["a"], &["a", "file"]] {
+                let names: Vec<OsString> = names.iter().map(OsString::from).collect();
+                let path = fst.entries(&names).unwrap().path();
+                let entry = scanner.scan_entry(dir, &path).unwrap();
+
+                assert!(fst.insert_entry(&names, entry));
+                assert_eq!(fst.root().size(), before, "{:?} with {:?}", names, attribution);
+
+                let a: Vec<&std::ffi::OsStr> = fst.entry(&OsString::from("a")).unwrap().children().map(|child| child.name() ).collect();
+                assert_eq!(a, ["file", "sub"], "{:?} with {:?}", names, attribution);
+            }
+        }
+    }
+
+    #[test]
+    fn scan_entry_shares_out_links() {
+        let tmp = TempDir::new("dredge").unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("file"), vec![1u8; 10_000]).unwrap();
+        fs::hard_link(dir.join("file"), dir.join("link")).unwrap();
+
+        let entry = Scanner::new().hard_links(LinkAttribution::Split)
+            .scan_entry(dir, dir.join("link"))
+            .unwrap();
+        assert_eq!(entry.root().share(), Some(Share::Split(2)));
+
+        let entry = Scanner::new().scan_entry(dir, dir.join("link")).unwrap();
+        assert_eq!(entry.root().share(), Some(Share::Whole));
+    }
+
+    #[test]
+    fn progress_counts() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = TempDir::new("dredge").unwrap();
+        let root = tmp.path().join("root");
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::create_dir_all(tmp.path().join("outside")).unwrap();
+        fs::write(root.join("one"), b"1").unwrap();
+        fs::write(root.join("dir/two"), b"2").unwrap();
+        fs::write(tmp.path().join("outside/three"), b"3").unwrap();
+        symlink("one", root.join("link")).unwrap();
+        symlink("../outside", root.join("followed")).unwrap();
+        symlink("nowhere", root.join("dangling")).unwrap();
+
+        let mut last = Progress::default();
+        Scanner::new()
+            .follow_symlinks(true)
+            .on_progress(Duration::from_secs(60), |progress| last = progress.clone() )
+            .scan(&root)
+            .unwrap();
+
+        // the symlinks aren't files, but what's behind the followed one is
+        assert_eq!((last.files, last.dirs, last.errors), (3, 3, 0));
+    }
+
+    #[test]
+    fn rescan_under_a_cycle() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = TempDir::new("dredge").unwrap();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/b/file"), vec![1u8; 10_000]).unwrap();
+        symlink("..", root.join("a/b/up")).unwrap();
+        symlink("../..", root.join("a/b/top")).unwrap();
+
+        let scanner = || Scanner::new().follow_symlinks(true);
+        let fst = scanner().scan(root).unwrap();
+        let mut whole = Vec::new();
+        flatten(fst.entries(&[OsString::from("a"), OsString::from("b")]).unwrap(), root, &mut whole);
+
+        // reading `a/b` again turns up the same links back up the tree,
+        // rather than following them around once more first
+        let b = scanner().scan_entry(root, root.join("a/b")).unwrap();
+        let mut again = Vec::new();
+        flatten(b.root(), root, &mut again);
+
+        assert_eq!(again, whole);
+        assert!(again.iter().any(|&(ref path, _, kind)| path.ends_with("up") && kind == EntryKind::Cycle ));
+        assert!(again.iter().any(|&(ref path, _, kind)| path.ends_with("top") && kind == EntryKind::Cycle ));
+
+        let up = scanner().scan_entry(root, root.join("a/b/up")).unwrap();
+        assert_eq!(up.root().kind(), EntryKind::Cycle);
+    }
+
+    #[test]
+    fn patterns_with_slashes_start_at_the_root() {
+        // somewhere relative, as in `dredge t --exclude a/sub`
+        let here = std::env::current_dir().unwrap();
+        let tmp = TempDir::new_in(&here, "dredge").unwrap();
+        let root = tmp.path().strip_prefix(&here).unwrap().join("t");
+        fs::create_dir_all(root.join("a/sub")).unwrap();
+        fs::create_dir_all(root.join("b/a/sub")).unwrap();
+
+        let filter = Filter {
+            exclude: vec![Pattern::new("a/sub").unwrap()],
+            include: Vec::new(),
+        };
+
+        let scanner = || Scanner::new().filter(filter.clone(), false);
+        let fst = scanner().scan(&root).unwrap();
+        let mut lines = Vec::new();
+        flatten(fst.root(), &root, &mut lines);
+        let paths: Vec<PathBuf> = lines.into_iter().map(|(path, _, _)| path ).collect();
+
+        assert!(paths.contains(&root.join("a")));
+        assert!(!paths.contains(&root.join("a/sub")));
+        assert!(paths.contains(&root.join("b/a/sub")));
+
+        // and the same for anything read in later
+        assert!(scanner().scan_entry(&root, root.join("a/sub")).is_none());
+        let a = scanner().scan_entry(&root, root.join("a")).unwrap();
+        assert_eq!(a.root().children().count(), 0);
+    }
+}
@@ -0,0 +1,347 @@
+// Copyright (C) 2016  Michael Dunsmuir
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Saving scanned trees to a file and reading them back. The format is
+// described in the README, under "Snapshot format"; keep the two in step.
+
+extern crate std;
+
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use super::fstree::{Contents, Entry, EntryKind, FSTree, Node, Share, Size, SpecialKind};
+
+/// What every snapshot starts with.
+const MAGIC: &[u8; 6] = b"dredge";
+
+/// The version of the format that's written, and the only one we can read.
+const VERSION: u16 = 1;
+
+/// The modification time recorded for anything that doesn't have one.
+const NO_MTIME: i64 = i64::MIN;
+
+/// The kinds of entry, in the order of their codes in the file.
+const KINDS: [EntryKind; 12] = [
+    EntryKind::Dir,
+    EntryKind::File,
+    EntryKind::Symlink,
+    EntryKind::Linked,
+    EntryKind::Cycle,
+    EntryKind::MountPoint,
+    EntryKind::Excluded,
+    EntryKind::Special(SpecialKind::Fifo),
+    EntryKind::Special(SpecialKind::Socket),
+    EntryKind::Special(SpecialKind::BlockDevice),
+    EntryKind::Special(SpecialKind::CharDevice),
+    EntryKind::Bad,
+];
+
+/// The errors we can name in a snapshot. Anything else comes back as
+/// `Other`.
+const ERRORS: [io::ErrorKind; 39] = {
+    use std::io::ErrorKind::*;
+
+    [
+        NotFound, PermissionDenied, ConnectionRefused, ConnectionReset,
+        HostUnreachable, NetworkUnreachable, ConnectionAborted, NotConnected,
+        AddrInUse, AddrNotAvailable, NetworkDown, BrokenPipe, AlreadyExists,
+        WouldBlock, NotADirectory, IsADirectory, DirectoryNotEmpty,
+        ReadOnlyFilesystem, StaleNetworkFileHandle, InvalidInput, InvalidData,
+        TimedOut, WriteZero, StorageFull, NotSeekable, QuotaExceeded,
+        FileTooLarge, ResourceBusy, ExecutableFileBusy, Deadlock,
+        CrossesDevices, TooManyLinks, InvalidFilename, ArgumentListTooLong,
+        Interrupted, Unsupported, UnexpectedEof, OutOfMemory, Other,
+    ]
+};
+
+/// Write the whole of `fst` to `out`.
+pub fn write<W: Write>(fst: &FSTree, out: W) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    write_bytes(&mut out, fst.path().as_os_str())?;
+    out.write_all(&fst.root().mtime().unwrap_or(NO_MTIME).to_le_bytes())?;
+    write_dir(&mut out, fst.root())?;
+
+    out.flush()
+}
+
+/// Read back a tree written by `write`.
+pub fn read<R: Read>(input: R) -> io::Result<FSTree> {
+    let mut input = BufReader::new(input);
+
+    let mut magic = [0; 6];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a dredge snapshot".to_string()));
+    }
+
+    let mut version = [0; 2];
+    input.read_exact(&mut version)?;
+    let version = u16::from_le_bytes(version);
+    if version != VERSION {
+        return Err(invalid(format!("unsupported snapshot version {}", version)));
+    }
+
+    let root = PathBuf::from(OsString::from_vec(read_bytes(&mut input)?));
+    let mtime = Some(read_i64(&mut input)?).filter(|&mtime| mtime != NO_MTIME );
+
+    let name = root.file_name().unwrap_or(root.as_os_str()).to_os_string();
+    let top = Entry::new(name, EntryKind::Dir, Size::default()).with_mtime(mtime);
+    let mut fst = FSTree::from_entry(root, top);
+
+    read_dir(&mut input, &mut fst, &mut Vec::new())?;
+    Ok(fst)
+}
+
+// a directory's entries, followed by the contents of each of them that has
+// any, in the same order
+fn write_dir<W: Write>(out: &mut W, dir: Node) -> io::Result<()> {
+    let children: Vec<Node> = dir.children().collect();
+
+    write_u32(out, children.len() as u32)?;
+    for node in &children {
+        write_entry(out, *node)?;
+    }
+
+    for node in children.iter().filter(|node| node.has_contents() ) {
+        write_dir(out, *node)?;
+    }
+
+    Ok(())
+}
+
+fn write_entry<W: Write>(out: &mut W, node: Node) -> io::Result<()> {
+    let kind = node.kind();
+    let code = KINDS.iter().position(|&k| k == kind ).unwrap();
+
+    out.write_all(&[code as u8])?;
+    write_bytes(out, node.name())?;
+    out.write_all(&node.mtime().unwrap_or(NO_MTIME).to_le_bytes())?;
+
+    if has_size(kind) {
+        let size = node.whole_size().unwrap_or_default();
+        out.write_all(&size.disk.to_le_bytes())?;
+        out.write_all(&size.apparent.to_le_bytes())?;
+    }
+
+    if kind == EntryKind::File {
+        write_u32(out, node.links().unwrap_or(1) as u32)?;
+        out.write_all(&[match node.share() {
+            Some(Share::Duplicate) => 1,
+            Some(Share::Split(_)) => 2,
+            _ => 0,
+        }])?;
+    }
+
+    if has_target(kind) {
+        write_bytes(out, node.target().unwrap_or(Path::new("")).as_os_str())?;
+    }
+
+    if kind == EntryKind::Bad {
+        let error = format!("{:?}", node.error().unwrap_or(io::ErrorKind::Other));
+        write_bytes(out, OsStr::new(&error))?;
+    }
+
+    Ok(())
+}
+
+fn read_dir<R: Read>(input: &mut R, fst: &mut FSTree, names: &mut Vec<OsString>) -> io::Result<()> {
+    let count = read_u32(input)?;
+    let entries = (0..count)
+        .map(|_| read_entry(input) )
+        .collect::<io::Result<Vec<Entry>>>()?;
+
+    // the contents come in the order the entries did, which isn't
+    // necessarily the order they end up in
+    let subdirs: Vec<OsString> = entries.iter()
+        .filter(|entry| entry.has_contents() )
+        .map(|entry| entry.name().to_os_string() )
+        .collect();
+
+    fst.graft(names, Ok(Contents::from_entries(entries)));
+
+    for name in subdirs {
+        names.push(name);
+        read_dir(input, fst, names)?;
+        names.pop();
+    }
+
+    Ok(())
+}
+
+fn read_entry<R: Read>(input: &mut R) -> io::Result<Entry> {
+    let code = read_u8(input)?;
+    let kind = *KINDS.get(code as usize)
+        .ok_or_else(|| invalid(format!("unknown entry kind {}", code)) )?;
+
+    let name = OsString::from_vec(read_bytes(input)?);
+    let mtime = Some(read_i64(input)?).filter(|&mtime| mtime != NO_MTIME );
+
+    let size = if has_size(kind) {
+        Size {
+            disk: read_u64(input)?,
+            apparent: read_u64(input)?,
+        }
+    } else {
+        Size::default()
+    };
+
+    let mut entry = Entry::new(name, kind, size);
+
+    if kind == EntryKind::File {
+        let links = read_u32(input)? as u64;
+        let share = match read_u8(input)? {
+            0 => Share::Whole,
+            1 => Share::Duplicate,
+            2 => Share::Split(links),
+            n => return Err(invalid(format!("unknown hard link share {}", n))),
+        };
+
+        entry = entry.with_links(links, share);
+    }
+
+    if has_target(kind) {
+        entry = entry.with_target(PathBuf::from(OsString::from_vec(read_bytes(input)?)));
+    }
+
+    if kind == EntryKind::Bad {
+        let name = read_bytes(input)?;
+        let error = ERRORS.iter()
+            .find(|error| format!("{:?}", error).as_bytes() == &name[..] )
+            .cloned()
+            .unwrap_or(io::ErrorKind::Other);
+
+        entry = Entry::bad(entry.name().to_os_string(), error);
+    }
+
+    Ok(entry.with_mtime(mtime))
+}
+
+fn has_size(kind: EntryKind) -> bool {
+    matches!(kind, EntryKind::File | EntryKind::Symlink | EntryKind::Cycle | EntryKind::Special(_))
+}
+
+fn has_target(kind: EntryKind) -> bool {
+    matches!(kind, EntryKind::Symlink | EntryKind::Cycle | EntryKind::Linked)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_u32<W: Write>(out: &mut W, n: u32) -> io::Result<()> {
+    out.write_all(&n.to_le_bytes())
+}
+
+fn write_bytes<W: Write>(out: &mut W, bytes: &OsStr) -> io::Result<()> {
+    write_u32(out, bytes.len() as u32)?;
+    out.write_all(bytes.as_bytes())
+}
+
+fn read_u8<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    input.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_i64<R: Read>(input: &mut R) -> io::Result<i64> {
+    read_u64(input).map(|n| n as i64 )
+}
+
+fn read_bytes<R: Read>(input: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u32(input)? as usize;
+    let mut bytes = Vec::new();
+    input.take(len as u64).read_to_end(&mut bytes)?;
+
+    if bytes.len() == len {
+        Ok(bytes)
+    } else {
+        Err(io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::scan::Scanner;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn round_trip() {
+        let tmp = TempDir::new("dredge").unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("sub/deeper")).unwrap();
+        fs::write(dir.join("file"), vec![1u8; 10_000]).unwrap();
+        fs::write(dir.join("sub/deeper/other"), b"hello").unwrap();
+        fs::write(dir.join(OsStr::from_bytes(b"bad\xffname")), b"").unwrap();
+        std::os::unix::fs::symlink("file", dir.join("link")).unwrap();
+
+        let fst = Scanner::new().scan(dir).unwrap();
+        let mut written = Vec::new();
+        write(&fst, &mut written).unwrap();
+
+        let read_back = read(&written[..]).unwrap();
+        assert_eq!(read_back.path(), fst.path());
+        assert_eq!(read_back.root().size(), fst.root().size());
+        assert_eq!(read_back.root().mtime(), fst.root().mtime());
+
+        // everything that can be written out should survive the trip
+        let mut rewritten = Vec::new();
+        write(&read_back, &mut rewritten).unwrap();
+        assert!(written == rewritten);
+    }
+
+    #[test]
+    fn not_a_snapshot() {
+        assert!(read(&b""[..]).is_err());
+        assert!(read(&b"not a snapshot at all"[..]).is_err());
+
+        // the right start, but from some other version
+        let mut other = MAGIC.to_vec();
+        other.extend_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(read(&other[..]).is_err());
+    }
+
+    #[test]
+    fn cut_short() {
+        let tmp = TempDir::new("dredge").unwrap();
+        fs::create_dir_all(tmp.path().join("sub")).unwrap();
+        fs::write(tmp.path().join("sub/file"), b"hello").unwrap();
+
+        let mut written = Vec::new();
+        write(&Scanner::new().scan(tmp.path()).unwrap(), &mut written).unwrap();
+
+        for len in 0..written.len() {
+            assert!(read(&written[..len]).is_err(), "read {} bytes of {}", len, written.len());
+        }
+    }
+}
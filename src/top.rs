@@ -0,0 +1,195 @@
+// Copyright (C) 2016  Michael Dunsmuir
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate std;
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::ffi::OsString;
+
+use super::fstree::{EntryKind, FSTree, Node, SizeMode};
+
+/// An entry from somewhere in a tree, and the size it was ranked by.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Ranked {
+    pub size: u64,
+
+    /// The names leading from the top of the tree down to the entry.
+    pub names: Vec<OsString>,
+}
+
+/// The biggest files anywhere in a tree, and the directories with the most
+/// in them, biggest first.
+pub struct Largest {
+    pub files: Vec<Ranked>,
+
+    /// Directories go by their own size: what's directly inside them, not
+    /// counting what's in the directories inside them. Otherwise the top
+    /// of the tree would always win, followed by whatever's on the way
+    /// down to the biggest file.
+    pub dirs: Vec<Ranked>,
+}
+
+impl Largest {
+
+    /// Find the `n` biggest files and directories in `fst`. What's behind
+    /// followed symlinks is left out, like it is from the totals, since it's
+    /// somewhere else entirely (or somewhere else in the tree).
+    pub fn find(fst: &FSTree, n: usize, size_mode: SizeMode) -> Self {
+        let mut files = BinaryHeap::new();
+        let mut dirs = BinaryHeap::new();
+
+        // a top level has nothing directly in it, and isn't anywhere to go
+        if fst.is_top_level() {
+            for dir in fst.root().children().filter(|dir| dir.has_contents() ) {
+                let mut names = vec![dir.name().to_os_string()];
+                find_in(dir, &mut names, n, size_mode, &mut files, &mut dirs);
+            }
+        } else {
+            find_in(fst.root(), &mut Vec::new(), n, size_mode, &mut files, &mut dirs);
+        }
+
+        Largest {
+            files: sorted(files),
+            dirs: sorted(dirs),
+        }
+    }
+}
+
+// the heaps hold the smallest of what's been kept so far on top, ready to
+// make way for something bigger
+type Heap = BinaryHeap<Reverse<Ranked>>;
+
+fn find_in(
+    dir: Node,
+    names: &mut Vec<OsString>,
+    n: usize,
+    size_mode: SizeMode,
+    files: &mut Heap,
+    dirs: &mut Heap,
+) {
+    let mut own_size = 0;
+
+    for child in dir.children() {
+        if child.has_contents() {
+            if !child.is_linked() {
+                names.push(child.name().to_os_string());
+                find_in(child, names, n, size_mode, files, dirs);
+                names.pop();
+            }
+        } else {
+            let size = child.size().unwrap_or_default().get(size_mode);
+            own_size += size;
+
+            if child.kind() == EntryKind::File {
+                keep(files, n, size, || {
+                    let mut names = names.clone();
+                    names.push(child.name().to_os_string());
+                    names
+                });
+            }
+        }
+    }
+
+    keep(dirs, n, own_size, || names.clone() );
+}
+
+// add whatever's at the names `names` gives to `heap`, if it's one of the
+// biggest `n`, only working out the names if it is
+fn keep<F>(heap: &mut Heap, n: usize, size: u64, names: F)
+    where F: FnOnce() -> Vec<OsString>
+{
+    if let Some(smallest) = heap.peek() {
+        if heap.len() >= n && smallest.0.size >= size {
+            return;
+        }
+    }
+
+    if n > 0 {
+        heap.push(Reverse(Ranked { size, names: names() }));
+
+        if heap.len() > n {
+            heap.pop();
+        }
+    }
+}
+
+fn sorted(heap: Heap) -> Vec<Ranked> {
+    // ascending order of `Reverse` is biggest first
+    heap.into_sorted_vec().into_iter().map(|ranked| ranked.0 ).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::fstree::{Contents, Entry, Size};
+    use std::path::PathBuf;
+
+    fn file(name: &str, n: u64) -> Entry {
+        Entry::new(OsString::from(name), EntryKind::File, Size { disk: n, apparent: n })
+    }
+
+    fn dir(name: &str, kind: EntryKind) -> Entry {
+        Entry::new(OsString::from(name), kind, Size::default())
+    }
+
+    fn names(path: &str) -> Vec<OsString> {
+        path.split('/').filter(|name| !name.is_empty() ).map(OsString::from).collect()
+    }
+
+    // /a 100, /b 5, /x/c 300, /x/d 50, /x/y/e 200, and a followed symlink
+    // to somewhere with a much bigger file in it
+    fn tree() -> FSTree {
+        let mut fst = FSTree::from_entry(PathBuf::from("/top"), dir("top", EntryKind::Dir));
+        let graft = |fst: &mut FSTree, path: &str, entries: Vec<Entry>| {
+            assert!(fst.graft(&names(path), Ok(Contents::from_entries(entries))));
+        };
+
+        graft(&mut fst, "", vec![file("a", 100), file("b", 5), dir("x", EntryKind::Dir), dir("link", EntryKind::Linked)]);
+        graft(&mut fst, "x", vec![file("c", 300), file("d", 50), dir("y", EntryKind::Dir)]);
+        graft(&mut fst, "x/y", vec![file("e", 200)]);
+        graft(&mut fst, "link", vec![file("huge", 10_000)]);
+        fst
+    }
+
+    fn ranked(list: &[Ranked]) -> Vec<(u64, Vec<OsString>)> {
+        list.iter().map(|ranked| (ranked.size, ranked.names.clone()) ).collect()
+    }
+
+    #[test]
+    fn biggest_first() {
+        let largest = Largest::find(&tree(), 2, SizeMode::Disk);
+
+        assert_eq!(ranked(&largest.files), [(300, names("x/c")), (200, names("x/y/e"))]);
+        assert_eq!(ranked(&largest.dirs), [(350, names("x")), (200, names("x/y"))]);
+    }
+
+    #[test]
+    fn fewer_than_asked_for() {
+        let largest = Largest::find(&tree(), 10, SizeMode::Apparent);
+
+        let files: Vec<u64> = largest.files.iter().map(|ranked| ranked.size ).collect();
+        assert_eq!(files, [300, 200, 100, 50, 5]);
+
+        // the top of the tree only counts what's directly in it
+        assert_eq!(ranked(&largest.dirs), [(350, names("x")), (200, names("x/y")), (105, names(""))]);
+    }
+
+    #[test]
+    fn none_asked_for() {
+        let largest = Largest::find(&tree(), 0, SizeMode::Disk);
+        assert!(largest.files.is_empty() && largest.dirs.is_empty());
+    }
+}
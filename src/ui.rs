@@ -17,41 +17,237 @@ extern crate std;
 
 use super::*;
 
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
 use rustbox::Event::KeyEvent;
 use rustbox::keyboard::Key::*;
 
+/// How often to redraw while a background scan is filling in the tree.
+const SCAN_REFRESH: u64 = 100; // ms
+
+/// How many of the biggest files and directories to list.
+const LARGEST: usize = 100;
+
+/// How often the biggest files and directories are looked for again while
+/// the tree keeps changing, since it means going through all of it.
+const LARGEST_REFRESH: u64 = 2000; // ms
+
+/// The order entries are listed in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SortMode {
+    /// Biggest first.
+    Size,
+
+    /// Highest ratio of disk usage to apparent size first.
+    Inflated,
+
+    /// Lowest ratio of disk usage to apparent size first.
+    Sparse,
+
+    /// Whatever's grown the most first (only when comparing two scans).
+    Growth,
+}
+
+/// How to show names that aren't valid UTF-8, or that have control
+/// characters in them that would mess up the screen.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NameDisplay {
+    /// Show the offending bytes as `\xNN`. Backslashes are doubled, so
+    /// every name shows up differently and nothing is lost.
+    Escape,
+
+    /// Show a replacement character (�) instead, which is easier on the
+    /// eye but can make different names look the same.
+    Replace,
+}
+
+impl NameDisplay {
+
+    /// Turn a name or path into something that's safe to print. The name
+    /// itself is left alone, so it still refers to the same file.
+    pub fn show<S: AsRef<OsStr>>(self, name: S) -> String {
+        let mut bytes = name.as_ref().as_bytes();
+        let mut shown = String::with_capacity(bytes.len());
+
+        while !bytes.is_empty() {
+            // the longest valid stretch, and how many bad bytes follow it
+            let (valid, invalid) = match std::str::from_utf8(bytes) {
+                Ok(valid) => (valid, 0),
+                Err(e) => (
+                    std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap(),
+                    e.error_len().unwrap_or(bytes.len() - e.valid_up_to()),
+                ),
+            };
+
+            for c in valid.chars() {
+                match self {
+                    NameDisplay::Escape if c == '\\' => shown.push_str("\\\\"),
+
+                    NameDisplay::Escape if c.is_control() => {
+                        let mut buf = [0; 4];
+                        for b in c.encode_utf8(&mut buf).bytes() {
+                            shown.push_str(&format!("\\x{:02x}", b));
+                        }
+                    }
+
+                    NameDisplay::Replace if c.is_control() =>
+                        shown.push(std::char::REPLACEMENT_CHARACTER),
+
+                    _ => shown.push(c),
+                }
+            }
+
+            let (bad, rest) = bytes[valid.len()..].split_at(invalid);
+
+            match self {
+                NameDisplay::Escape => for b in bad {
+                    shown.push_str(&format!("\\x{:02x}", b));
+                },
+
+                NameDisplay::Replace => if !bad.is_empty() {
+                    shown.push(std::char::REPLACEMENT_CHARACTER);
+                },
+            }
+
+            bytes = rest;
+        }
+
+        shown
+    }
+}
+
 pub struct UI<'a> {
     fst: FSTree,
+    scan: Option<Scan<'a>>,
+    scanner: &'a ScannerFactory<'a>,
+    watcher: Option<Watcher<'a>>,
     rustbox: &'a rustbox::RustBox,
     stack: Vec<std::ffi::OsString>,
     listing: Vec<Listing>,
     selected: Vec<Option<usize>>,
     window_top: usize,
+    size_mode: SizeMode,
+    sort: SortMode,
+    show_ratio: bool,
+    names: NameDisplay,
+
+    // set when the tree didn't come from the disk, so there's nothing
+    // there to delete or rescan
+    read_only: bool,
+
+    // an earlier scan of the same tree, which `fst` is being compared with
+    base: Option<FSTree>,
+
+    // the entries picked out for deleting all at once, by their names from
+    // the top of the tree. Being sorted, anything inside a marked directory
+    // comes after it.
+    marked: BTreeSet<Vec<OsString>>,
 }
 
 impl<'a> UI<'a> {
 
-    pub fn new(rustbox: &'a rustbox::RustBox, fsts: FSTree) -> Self {
+    /// `scan` should be the background scan filling in `fsts`, if there is
+    /// one. The tree is updated as results come in. `size_mode` is just the
+    /// initial choice; the user can switch back and forth. `names` says how
+    /// to show names that can't be printed as they are. `scanner` is used
+    /// for reading parts of the tree again.
+    pub fn new(
+        rustbox: &'a rustbox::RustBox,
+        fsts: FSTree,
+        scan: Option<Scan<'a>>,
+        scanner: &'a ScannerFactory<'a>,
+        size_mode: SizeMode,
+        names: NameDisplay,
+    ) -> Self {
         let mut ui = UI {
             fst: fsts,
+            scan,
+            scanner,
+            watcher: None,
             rustbox: rustbox,
             stack: Vec::new(),
             listing: Vec::new(),
             selected: vec![None],
             window_top: 0,
+            size_mode,
+            sort: SortMode::Size,
+            show_ratio: false,
+            names,
+            read_only: false,
+            base: None,
+            marked: BTreeSet::new(),
         };
 
         ui.load();
         ui
     }
 
+    /// Don't delete or rescan anything, e.g. because the tree was loaded
+    /// from a snapshot.
+    pub fn read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    /// Show how the tree has changed since `base` was scanned, biggest
+    /// growth first, along with whatever's been added or removed.
+    pub fn compare(&mut self, base: FSTree) {
+        self.base = Some(base);
+        self.sort = SortMode::Growth;
+        self.load();
+    }
+
+    /// Keep the tree up to date with `watcher` from now on (or once the
+    /// background scan is done, if it's still going).
+    pub fn watch(&mut self, mut watcher: Watcher<'a>) {
+        if self.scan.is_none() {
+            watcher.watch(&self.fst);
+        }
+
+        self.watcher = Some(watcher);
+    }
+
     pub fn load(&mut self) {
-        self.listing = self.fst.entries(self.stack.as_slice())
-                               .and_then(|fst| fst.list() )
-                               .unwrap();
+        // the directory we were looking at might not be there any more
+        self.listing = loop {
+            match self.list(&self.stack) {
+                Some(listing) => break listing,
+
+                None => {
+                    self.stack.pop();
+                    self.selected.pop();
+                }
+            }
+        };
+
+        let size_mode = self.size_mode;
+        match self.sort {
+            SortMode::Size => {
+                self.listing.sort_by_key(|listing| listing.size.get(size_mode) );
+                self.listing.reverse();
+            }
 
-        self.listing.sort_by_key(|&(_, size, _, _)| size );
-        self.listing.reverse();
+            SortMode::Inflated => self.listing.sort_by(|a, b|
+                Self::compare_ratios(a, b, |a, b| b.partial_cmp(&a) )
+            ),
+
+            SortMode::Sparse => self.listing.sort_by(|a, b|
+                Self::compare_ratios(a, b, |a, b| a.partial_cmp(&b) )
+            ),
+
+            SortMode::Growth => {
+                self.listing.sort_by_key(|listing|
+                    listing.change.map(|change| change.delta(size_mode) ).unwrap_or(0)
+                );
+                self.listing.reverse();
+            }
+        }
 
         if !self.listing.is_empty() { // if there are items to show
             let n_listings = self.listing.len();
@@ -74,12 +270,107 @@ impl<'a> UI<'a> {
         }
     }
 
+    // what's in the directory at `names`, compared with the earlier scan if
+    // there is one
+    fn list(&self, names: &[OsString]) -> Option<Vec<Listing>> {
+        match self.base {
+            Some(ref base) => DiffNode::new(base, &self.fst).entries(names)
+                .and_then(|diff| diff.list() ),
+
+            None => self.fst.entries(names).and_then(|node| node.list() ),
+        }
+    }
+
+    // things without a ratio always go at the end, whichever way we're
+    // sorting the rest
+    fn compare_ratios<F>(a: &Listing, b: &Listing, compare: F) -> Ordering
+        where F: Fn(f64, f64) -> Option<Ordering>
+    {
+        match (a.size.ratio(), b.size.ratio()) {
+            (Some(a), Some(b)) => compare(a, b).unwrap_or(Ordering::Equal),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+
+    /// Reload the current listing in place, keeping the same entry selected
+    /// if it's still there even if it has moved.
+    fn refresh(&mut self) {
+        let selected_name = self.selected().map(|pos| self.listing[pos].name.clone() );
+
+        // anything marked might have gone in the meantime
+        let fst = &self.fst;
+        self.marked.retain(|names| fst.entries(names).is_some() );
+
+        self.load();
+
+        let new_pos = selected_name.and_then(|name|
+            self.listing.iter().position(|listing| listing.name == name )
+        );
+
+        if new_pos.is_some() {
+            *self.selected_mut() = new_pos;
+        }
+    }
+
+    /// Pull in whatever the background scan has found since last time.
+    /// Returns `true` if anything changed.
+    fn poll_scan(&mut self) -> bool {
+        let changed = match self.scan {
+            Some(ref mut scan) => scan.poll(&mut self.fst),
+            None => false,
+        };
+
+        if self.scan.as_ref().map(|scan| scan.is_done() ).unwrap_or(false) {
+            self.scan = None;
+
+            if let Some(ref mut watcher) = self.watcher {
+                watcher.watch(&self.fst);
+            }
+        }
+
+        if changed {
+            self.refresh();
+        }
+
+        changed
+    }
+
+    /// Apply any changes the watcher has seen. Returns `true` if anything
+    /// changed.
+    fn poll_watcher(&mut self) -> bool {
+        // nothing's watched until the scan's done
+        let changed = match (&self.scan, &mut self.watcher) {
+            (&None, &mut Some(ref mut watcher)) => watcher.poll(&mut self.fst),
+            _ => false,
+        };
+
+        if changed {
+            self.refresh();
+        }
+
+        changed
+    }
+
+    // while scanning or watching we can't just sit and wait for the user,
+    // we have to come back regularly to keep the tree up to date
+    fn next_event(&self) -> rustbox::EventResult {
+        if self.scan.is_some() || self.watcher.is_some() {
+            self.rustbox.peek_event(Duration::from_millis(SCAN_REFRESH), false)
+        } else {
+            self.rustbox.poll_event(false)
+        }
+    }
+
     pub fn event_loop(&mut self) {
         loop {
+            self.poll_scan();
+            self.poll_watcher();
             self.align_viewport();
             self.draw();
 
-            match self.rustbox.poll_event(false) {
+            match self.next_event() {
                 Ok(KeyEvent(Char('q'))) => break,
 
                 Ok(KeyEvent(Char('k'))) => self.scroll(-1),
@@ -91,8 +382,8 @@ impl<'a> UI<'a> {
                 Ok(KeyEvent(Char('l'))) => {
                     if let &Some(pos) = self.selected() {
                         let (name, is_dir) = {
-                            let ref target = self.listing[pos];
-                            (target.0.clone(), target.2)
+                            let target = &self.listing[pos];
+                            (target.name.clone(), target.is_dir)
                         };
 
                         if is_dir {
@@ -110,7 +401,53 @@ impl<'a> UI<'a> {
                     }
                 },
 
-                Ok(KeyEvent(Char('d'))) => self.delete(),
+                Ok(KeyEvent(Char('d'))) if !self.read_only => self.delete(),
+
+                Ok(KeyEvent(Char('m'))) if !self.read_only => {
+                    self.toggle_mark();
+                    self.scroll(1);
+                }
+
+                Ok(KeyEvent(Char('M'))) if !self.read_only => self.show_marked(),
+
+                Ok(KeyEvent(Char('a'))) => {
+                    self.size_mode = match self.size_mode {
+                        SizeMode::Disk => SizeMode::Apparent,
+                        SizeMode::Apparent => SizeMode::Disk,
+                    };
+
+                    self.refresh();
+                }
+
+                Ok(KeyEvent(Char('s'))) => {
+                    self.sort = match self.sort {
+                        SortMode::Size => SortMode::Inflated,
+                        SortMode::Inflated => SortMode::Sparse,
+                        SortMode::Sparse if self.base.is_some() => SortMode::Growth,
+                        SortMode::Sparse | SortMode::Growth => SortMode::Size,
+                    };
+
+                    self.refresh();
+                }
+
+                Ok(KeyEvent(Char('%'))) => self.show_ratio = !self.show_ratio,
+
+                Ok(KeyEvent(Char('e'))) => self.show_errors(),
+
+                Ok(KeyEvent(Char('t'))) => self.show_largest(),
+
+                Ok(KeyEvent(Char('r'))) if !self.read_only => {
+                    let names = self.stack.clone();
+                    self.rescan(&names);
+                }
+
+                Ok(KeyEvent(Char('R'))) if !self.read_only => {
+                    if let &Some(pos) = self.selected() {
+                        let mut names = self.stack.clone();
+                        names.push(self.listing[pos].name.clone());
+                        self.rescan(&names);
+                    }
+                }
 
                 _ => (),
             }
@@ -125,32 +462,34 @@ impl<'a> UI<'a> {
             Some(pos) => pos,
         };
 
+        let mut names = self.stack.clone();
+        names.push(self.listing[pos].name.clone());
+
+        // the directories under a top level can't be deleted
+        let top = self.fst.is_top_level() && names.len() == 1;
+
+        let path = match self.fst.entries(&names) {
+            Some(node) if !node.is_bad() && !top => node.path(),
+            _ => return,
+        };
+
         // we'll show the item being deleted in the status bar
         // this is a bit of a cheat becuase we need it to be on the stack
         // anyway for the rest of this method to work, but we'll push it
         // now to keep the UI looking consistent during deletion
-        self.stack.push(self.listing[pos].0.clone());
+        self.stack = names;
 
         // clear screen and show the prompt
         self.rustbox.clear();
         self.draw_status_bar(0);
 
-        let path = {
-            let fst = self.fst.entries(self.stack.as_slice()).unwrap();
-            if fst.is_bad() {
-                return
-            } else {
-                fst.path().unwrap().clone()
-            }
-        };
-
         let prompt = format!(
             "Really delete {} ? (y/N)",
-            path.to_str().unwrap()
+            self.names.show(&path)
         );
 
-        self.rustbox.print(
-            0, 1, rustbox::Style::empty(),
+        Self::print(
+            self.rustbox, 0, 1, rustbox::Style::empty(),
             rustbox::Color::Default,
             rustbox::Color::Default,
             &prompt
@@ -163,16 +502,15 @@ impl<'a> UI<'a> {
                 self.rustbox.clear();
                 self.draw_status_bar(0);
 
-                self.rustbox.print(
-                    0, 1, rustbox::Style::empty(),
+                Self::print(
+                    self.rustbox, 0, 1, rustbox::Style::empty(),
                     rustbox::Color::Default,
                     rustbox::Color::Default,
                     "deleting... this may take a little while"
                 );
 
                 self.rustbox.present();
-                self.fst.delete_path(self.stack.as_slice());
-                ()
+                let _ = self.fst.delete_path(self.stack.as_slice());
             },
 
             _ => (),
@@ -182,6 +520,467 @@ impl<'a> UI<'a> {
         self.load();
     }
 
+    /// Mark the selected entry for deleting later along with everything
+    /// else that's marked, or unmark it if it already is.
+    fn toggle_mark(&mut self) {
+        let mut names = self.stack.clone();
+        match *self.selected() {
+            Some(pos) => names.push(self.listing[pos].name.clone()),
+            None => return,
+        }
+
+        // only what's really there can be deleted, and not the directories
+        // under a top level
+        let top = self.fst.is_top_level() && names.len() == 1;
+        let deletable = !top && self.fst.entries(&names)
+            .map(|node| !node.is_bad() )
+            .unwrap_or(false);
+
+        if !self.marked.remove(&names) && deletable {
+            self.marked.insert(names);
+        }
+    }
+
+    // the marked entries that aren't inside other marked entries, which are
+    // all that need deleting (or counting)
+    fn marked_tops(&self) -> Vec<Vec<OsString>> {
+        self.marked.iter()
+            .filter(|names| !(1..names.len()).any(|n| self.marked.contains(&names[..n]) ) )
+            .cloned()
+            .collect()
+    }
+
+    // the size of the entry at `names`, or nothing if it's gone
+    fn size_of(&self, names: &[OsString]) -> u64 {
+        self.fst.entries(names)
+            .and_then(|node| node.size() )
+            .unwrap_or_default()
+            .get(self.size_mode)
+    }
+
+    /// Show everything that's marked, so it can be looked over, unmarked
+    /// and then deleted in one go.
+    fn show_marked(&mut self) {
+        let mut selected = 0;
+        let mut top = 0;
+
+        loop {
+            let marked: Vec<Vec<OsString>> = self.marked.iter().cloned().collect();
+            let height = self.rustbox.height().saturating_sub(2); // status bar and heading
+
+            selected = std::cmp::min(selected, marked.len().saturating_sub(1));
+            if selected < top {
+                top = selected;
+            } else if selected >= top + height {
+                top = selected + 1 - height;
+            }
+
+            self.rustbox.clear();
+            self.draw_status_bar(0);
+
+            Self::print(
+                self.rustbox, 0, 1, rustbox::RB_BOLD,
+                rustbox::Color::Default,
+                rustbox::Color::Default,
+                "marked (m to unmark, D to delete them all)"
+            );
+
+            if marked.is_empty() {
+                Self::print(
+                    self.rustbox, 0, 2, rustbox::Style::empty(),
+                    rustbox::Color::White,
+                    rustbox::Color::Default,
+                    "<nothing marked>"
+                );
+            }
+
+            for (i, names) in marked.iter().enumerate().skip(top).take(height) {
+                self.draw_path_line(i - top + 2, i == selected, names, self.size_of(names));
+            }
+
+            self.rustbox.present();
+
+            match self.next_event() {
+                Ok(KeyEvent(Char('k'))) => selected = selected.saturating_sub(1),
+                Ok(KeyEvent(Char('j'))) => selected += 1,
+                Ok(KeyEvent(PageUp)) => selected = selected.saturating_sub(height),
+                Ok(KeyEvent(PageDown)) => selected += height,
+
+                Ok(KeyEvent(Char('m'))) => {
+                    if let Some(names) = marked.get(selected) {
+                        self.marked.remove(names);
+                    }
+                }
+
+                Ok(KeyEvent(Enter)) | Ok(KeyEvent(Char('l'))) => {
+                    if let Some(names) = marked.get(selected) {
+                        self.jump_to(names);
+                        break;
+                    }
+                }
+
+                Ok(KeyEvent(Char('D'))) if !marked.is_empty() => {
+                    self.delete_marked();
+                    break;
+                }
+
+                Ok(KeyEvent(Char('M'))) | Ok(KeyEvent(Char('h'))) |
+                Ok(KeyEvent(Char('q'))) | Ok(KeyEvent(Esc)) => break,
+
+                _ => (),
+            }
+
+            self.poll_scan();
+            self.poll_watcher();
+        }
+    }
+
+    /// Delete everything that's marked, once the user has said so, and
+    /// show how each one went.
+    fn delete_marked(&mut self) {
+        let tops = self.marked_tops();
+        let size: u64 = tops.iter().map(|names| self.size_of(names) ).sum();
+
+        self.rustbox.clear();
+        self.draw_status_bar(0);
+
+        let prompt = format!(
+            "Really delete {} marked entries ({})? (y/N)",
+            tops.len(),
+            Self::format_size(size),
+        );
+
+        Self::print(
+            self.rustbox, 0, 1, rustbox::Style::empty(),
+            rustbox::Color::Default,
+            rustbox::Color::Default,
+            &prompt
+        );
+
+        self.rustbox.present();
+
+        match self.rustbox.poll_event(false) {
+            Ok(KeyEvent(Char('y'))) => (),
+            _ => return,
+        }
+
+        let mut results = Vec::new();
+
+        for (i, names) in tops.iter().enumerate() {
+            let path = names.iter()
+                .fold(self.fst.path().to_path_buf(), |path, name| path.join(name) );
+
+            self.rustbox.clear();
+            self.draw_status_bar(0);
+
+            Self::print(
+                self.rustbox, 0, 1, rustbox::Style::empty(),
+                rustbox::Color::Default,
+                rustbox::Color::Default,
+                &Self::truncate_middle(
+                    &format!("deleting {} of {}: {}", i + 1, tops.len(), self.names.show(&path)),
+                    self.rustbox.width(),
+                )
+            );
+
+            self.rustbox.present();
+
+            let result = match self.fst.delete_path(names) {
+                Ok(()) => format!("deleted {}", self.names.show(&path)),
+                Err(e) => format!("couldn't delete {}: {}", self.names.show(&path), e),
+            };
+
+            results.push(result);
+        }
+
+        // whatever was deleted (and everything in it) is gone, and whatever
+        // wasn't stays marked to try again
+        self.refresh();
+        self.show_results(&results);
+    }
+
+    /// Show how a batch of deletions went, until the user has seen enough.
+    fn show_results(&mut self, results: &[String]) {
+        let mut top = 0;
+
+        loop {
+            let height = self.rustbox.height() - 1; // minus one for status bar
+
+            self.rustbox.clear();
+            self.draw_status_bar(0);
+
+            for (i, result) in results.iter().skip(top).take(height).enumerate() {
+                Self::print(
+                    self.rustbox, 0, i + 1, rustbox::Style::empty(),
+                    rustbox::Color::Default,
+                    rustbox::Color::Default,
+                    &Self::truncate_middle(result, self.rustbox.width())
+                );
+            }
+
+            self.rustbox.present();
+
+            let last_top = results.len().saturating_sub(height);
+
+            match self.rustbox.poll_event(false) {
+                Ok(KeyEvent(Char('k'))) => top = top.saturating_sub(1),
+                Ok(KeyEvent(Char('j'))) => top = std::cmp::min(last_top, top + 1),
+                Ok(KeyEvent(PageUp)) => top = top.saturating_sub(height),
+                Ok(KeyEvent(PageDown)) => top = std::cmp::min(last_top, top + height),
+                Ok(KeyEvent(_)) => break,
+                _ => (),
+            }
+        }
+    }
+
+    /// Read the entry at `names` (and everything in it) again, in case it's
+    /// changed on disk, and put it back in the tree.
+    fn rescan(&mut self, names: &[std::ffi::OsString]) {
+        // the background scan is still filling things in, and wouldn't
+        // know what to make of a directory that's already been read
+        if self.scan.is_some() {
+            return;
+        }
+
+        let root = self.fst.scan_root(names);
+
+        // a top level is only the directories in it, so read all of those
+        if names.is_empty() && self.fst.is_top_level() {
+            let dirs: Vec<OsString> = self.fst.root().children()
+                .map(|dir| dir.name().to_os_string() )
+                .collect();
+
+            for dir in dirs {
+                self.rescan(&[dir]);
+            }
+
+            return;
+        }
+
+        if names.is_empty() {
+            if let Some(scanned) = (self.scanner)().scan(&root) {
+                self.fst.fill(scanned);
+            }
+        } else {
+            // the directories in a top level are roots of their own, and get
+            // followed the way `scan_all` does if they're symlinks
+            let entry = if names.len() == 1 && self.fst.is_top_level() {
+                (self.scanner)().scan(&root)
+            } else {
+                self.fst.entries(names)
+                    .map(|node| node.path() )
+                    .and_then(|path| (self.scanner)().scan_entry(&root, path) )
+            };
+
+            match entry {
+                Some(entry) => {
+                    self.fst.insert_entry(names, entry);
+                }
+
+                None => {
+                    self.fst.remove_entry(names);
+                }
+            }
+        }
+
+        if let Some(ref mut watcher) = self.watcher {
+            watcher.watch_entry(&self.fst, names);
+        }
+
+        self.refresh();
+    }
+
+    /// Show every entry that couldn't be read, and why, until the user has
+    /// seen enough.
+    fn show_errors(&mut self) {
+        let mut errors = self.fst.root().errors_list();
+        let mut top = 0;
+
+        loop {
+            let height = self.rustbox.height() - 1; // minus one for status bar
+
+            self.rustbox.clear();
+            self.draw_status_bar(0);
+
+            if errors.is_empty() {
+                Self::print(
+                    self.rustbox, 0, 1, rustbox::Style::empty(),
+                    rustbox::Color::White,
+                    rustbox::Color::Default,
+                    "<no errors>"
+                );
+            }
+
+            for (i, &(ref path, error)) in errors.iter().skip(top).take(height).enumerate() {
+                Self::print(
+                    self.rustbox, 0, i + 1, rustbox::Style::empty(),
+                    rustbox::Color::Default,
+                    rustbox::Color::Default,
+                    &Self::truncate_middle(
+                        &format!("{}: {}", self.names.show(path), error),
+                        self.rustbox.width(),
+                    )
+                );
+            }
+
+            self.rustbox.present();
+
+            let last_top = errors.len().saturating_sub(height);
+
+            match self.next_event() {
+                Ok(KeyEvent(Char('k'))) => top = top.saturating_sub(1),
+                Ok(KeyEvent(Char('j'))) => top = std::cmp::min(last_top, top + 1),
+                Ok(KeyEvent(PageUp)) => top = top.saturating_sub(height),
+                Ok(KeyEvent(PageDown)) => top = std::cmp::min(last_top, top + height),
+
+                Ok(KeyEvent(Char('e'))) | Ok(KeyEvent(Char('h'))) |
+                Ok(KeyEvent(Char('q'))) | Ok(KeyEvent(Esc)) => break,
+
+                _ => (),
+            }
+
+            if self.poll_scan() | self.poll_watcher() {
+                errors = self.fst.root().errors_list();
+            }
+        }
+    }
+
+    /// List the biggest files anywhere in the tree, or the directories
+    /// with the most directly in them, and go straight to whichever one the
+    /// user picks.
+    fn show_largest(&mut self) {
+        let mut largest = Largest::find(&self.fst, LARGEST, self.size_mode);
+        let mut found = Instant::now();
+        let mut stale = false;
+        let mut dirs = false;
+        let mut selected = 0;
+        let mut top = 0;
+
+        loop {
+            let height = self.rustbox.height().saturating_sub(2); // status bar and heading
+            let ranked = if dirs { &largest.dirs } else { &largest.files };
+
+            selected = std::cmp::min(selected, ranked.len().saturating_sub(1));
+            if selected < top {
+                top = selected;
+            } else if selected >= top + height {
+                top = selected + 1 - height;
+            }
+
+            self.rustbox.clear();
+            self.draw_status_bar(0);
+
+            Self::print(
+                self.rustbox, 0, 1, rustbox::RB_BOLD,
+                rustbox::Color::Default,
+                rustbox::Color::Default,
+                if dirs {
+                    "directories with the most directly in them (tab for files)"
+                } else {
+                    "largest files (tab for directories)"
+                }
+            );
+
+            for (i, ranked) in ranked.iter().enumerate().skip(top).take(height) {
+                self.draw_path_line(i - top + 2, i == selected, &ranked.names, ranked.size);
+            }
+
+            self.rustbox.present();
+
+            match self.next_event() {
+                Ok(KeyEvent(Char('k'))) => selected = selected.saturating_sub(1),
+                Ok(KeyEvent(Char('j'))) => selected += 1,
+                Ok(KeyEvent(PageUp)) => selected = selected.saturating_sub(height),
+                Ok(KeyEvent(PageDown)) => selected += height,
+
+                Ok(KeyEvent(Tab)) => {
+                    dirs = !dirs;
+                    selected = 0;
+                }
+
+                Ok(KeyEvent(Enter)) | Ok(KeyEvent(Char('l'))) => {
+                    if let Some(ranked) = ranked.get(selected) {
+                        let names = ranked.names.clone();
+                        self.jump_to(&names);
+                        break;
+                    }
+                }
+
+                Ok(KeyEvent(Char('t'))) | Ok(KeyEvent(Char('h'))) |
+                Ok(KeyEvent(Char('q'))) | Ok(KeyEvent(Esc)) => break,
+
+                _ => (),
+            }
+
+            stale |= self.poll_scan() | self.poll_watcher();
+
+            // a background scan changes things all the time, so wait for it
+            // to finish
+            let due = found.elapsed() >= Duration::from_millis(LARGEST_REFRESH);
+            if stale && due && self.scan.is_none() {
+                largest = Largest::find(&self.fst, LARGEST, self.size_mode);
+                found = Instant::now();
+                stale = false;
+            }
+        }
+    }
+
+    // a line with the path of the entry at `names` on the left and `size`
+    // on the right, for the screens that list entries from all over
+    fn draw_path_line(&self, y: usize, selected: bool, names: &[OsString], size: u64) {
+        let (front, back) = if selected {
+            (rustbox::Color::Black, rustbox::Color::White)
+        } else {
+            (rustbox::Color::Default, rustbox::Color::Default)
+        };
+
+        let path = names.iter()
+            .fold(self.fst.path().to_path_buf(), |path, name| path.join(name) );
+        let size_str = format!(" {:>10}", Self::format_size(size));
+        let size_str_x = self.rustbox.width().saturating_sub(size_str.width());
+
+        let path_str = Self::truncate_middle(&self.names.show(&path), size_str_x);
+        let path_end = Self::print(
+            self.rustbox, 0, y, rustbox::Style::empty(), front, back, &path_str
+        );
+
+        for col in path_end..size_str_x {
+            self.rustbox.print_char(col, y, rustbox::Style::empty(), front, back, ' ');
+        }
+
+        Self::print(
+            self.rustbox, size_str_x, y, rustbox::Style::empty(), front, back, &size_str
+        );
+    }
+
+    /// Open the directory the entry at `names` is in, with the entry
+    /// selected. Every directory on the way down is opened in turn, with
+    /// the way down selected, so going back up retraces the path.
+    fn jump_to(&mut self, names: &[OsString]) {
+        self.stack.clear();
+        self.selected = vec![None];
+        self.load();
+
+        if let Some((name, parents)) = names.split_last() {
+            for parent in parents {
+                self.select(parent);
+                self.stack.push(parent.clone());
+                self.selected.push(None);
+                self.load();
+            }
+
+            self.select(name);
+        }
+    }
+
+    fn select(&mut self, name: &OsStr) {
+        let pos = self.listing.iter().position(|listing| listing.name == name );
+
+        if pos.is_some() {
+            *self.selected_mut() = pos;
+        }
+    }
+
     fn selected(&self) -> &Option<usize> {
         // unwrapping in these methods should be fine because we well always
         // have at least one level pushed to the line selection stack
@@ -229,12 +1028,14 @@ impl<'a> UI<'a> {
         self.rustbox.clear();
 
         match self.selected().as_ref() {
-            None => self.rustbox.print(
-                0, 1, rustbox::Style::empty(),
-                rustbox::Color::White,
-                rustbox::Color::Default,
-                "<no files>"
-            ),
+            None => {
+                Self::print(
+                    self.rustbox, 0, 1, rustbox::Style::empty(),
+                    rustbox::Color::White,
+                    rustbox::Color::Default,
+                    "<no files>"
+                );
+            }
 
             Some(&i_selected) => {
                 // subtract one so the status bar fits
@@ -245,7 +1046,11 @@ impl<'a> UI<'a> {
                     &self.listing[self.window_top..last_index];
 
                 for (i, line) in to_display.iter().enumerate() {
-                    self.draw_line(i + 1, i + self.window_top == i_selected, line);
+                    let mut names = self.stack.clone();
+                    names.push(line.name.clone());
+                    let marked = self.marked.contains(&names);
+
+                    self.draw_line(i + 1, i + self.window_top == i_selected, marked, line);
                 }
             }
         }
@@ -256,38 +1061,107 @@ impl<'a> UI<'a> {
 
     fn draw_status_bar(&self, y: usize) {
         let status_str = {
-            let root_path = self.fst.path().unwrap();
-            let root_size = self.fst.size().unwrap();
-
-            let cur_fst = self.fst.entries(self.stack.as_slice()).unwrap();
-            let cur_size = cur_fst.size().unwrap();
-            let cur_path = cur_fst.path().unwrap();
+            let root_path = self.fst.path();
+
+            // the directory we're in might only be in the earlier scan, so
+            // put its path together from the names
+            let cur_path = self.stack.iter()
+                .fold(root_path.to_path_buf(), |path, name| path.join(name) );
+
+            // a top level doesn't have a path, so it goes by the
+            // directories in it
+            let root_str = if self.fst.is_top_level() {
+                self.fst.root().children()
+                    .map(|dir| self.names.show(dir.name()) )
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            } else {
+                self.names.show(root_path)
+            };
 
             // if we're at the root, there's no path worth showing
-            if self.stack.is_empty() {
+            let mut status_str = if self.stack.is_empty() {
                 format!("{} : {}",
-                    root_path.to_str().unwrap(),
-                    Self::format_size(root_size),
+                    root_str,
+                    self.total_size(&[]),
                 )
 
             } else {
                 format!(
                     "{} : {} | {} : {}",
-                    root_path.to_str().unwrap(),
-                    Self::format_size(root_size),
-                    cur_path.to_str().unwrap(),
-                    Self::format_size(cur_size),
+                    root_str,
+                    self.total_size(&[]),
+                    self.names.show(&cur_path),
+                    self.total_size(&self.stack),
                 )
+            };
+
+            if self.size_mode == SizeMode::Apparent {
+                status_str.push_str(" (apparent)");
+            }
+
+            match self.sort {
+                SortMode::Size if self.base.is_some() => status_str.push_str(" | biggest first"),
+                SortMode::Size | SortMode::Growth => (),
+                SortMode::Inflated => status_str.push_str(" | most inflated first"),
+                SortMode::Sparse => status_str.push_str(" | most sparse first"),
             }
+
+            if self.base.is_some() {
+                status_str.push_str(" | comparing with earlier scan");
+            }
+
+            if !self.marked.is_empty() {
+                let tops = self.marked_tops();
+                let size: u64 = tops.iter().map(|names| self.size_of(names) ).sum();
+                status_str.push_str(&format!(
+                    " | marked: {} items, {}", tops.len(), Self::format_size(size)
+                ));
+            }
+
+            // whatever's behind a followed symlink doesn't count towards the
+            // root, so make it obvious when we're in there
+            let linked = (1..self.stack.len() + 1).any(|depth|
+                self.fst.entries(&self.stack[..depth])
+                    .map(|node| node.is_linked() )
+                    .unwrap_or(false)
+            );
+
+            if linked {
+                status_str.push_str(" | via symlink, not counted");
+            }
+
+            let errors = self.fst.root().error_count();
+            if errors > 0 {
+                status_str.push_str(&format!(" | {} errors", errors));
+            }
+
+            if let Some(ref scan) = self.scan {
+                status_str.push_str(
+                    &format!(" | scanning... {} files", scan.progress().files)
+                );
+            } else if let Some(ref watcher) = self.watcher {
+                status_str.push_str(if watcher.is_complete() {
+                    " | watching"
+                } else {
+                    " | watching (missing some changes)"
+                });
+            }
+
+            if self.read_only {
+                status_str.push_str(" | read-only");
+            }
+
+            Self::truncate_middle(&status_str, self.rustbox.width())
         };
 
-        self.rustbox.print(
-            0, y, rustbox::Style::empty(),
+        let end = Self::print(
+            self.rustbox, 0, y, rustbox::Style::empty(),
             rustbox::Color::Default, rustbox::Color::Red,
             &status_str
         );
 
-        for col in status_str.len()..self.rustbox.width() {
+        for col in end..self.rustbox.width() {
             self.rustbox.print_char(
                 col, y, rustbox::Style::empty(),
                 rustbox::Color::Default, rustbox::Color::Red, ' '
@@ -295,35 +1169,82 @@ impl<'a> UI<'a> {
         }
     }
 
-    fn draw_line(&self, y: usize, selected: bool, listing: &Listing) {
-        // set colors depending on whether this line is selected
-        let (front, back) = if selected {
-            (rustbox::Color::Black, rustbox::Color::White)
+    // the size of the entry at `names`, and how much it's changed if we're
+    // comparing scans
+    fn total_size(&self, names: &[OsString]) -> String {
+        match self.base {
+            Some(ref base) => {
+                let change = DiffNode::new(base, &self.fst).entries(names)
+                    .map(|diff| diff.change() )
+                    .unwrap_or(Change { old: None, new: None });
+
+                format!(
+                    "{} ({})",
+                    Self::format_size(change.new.unwrap_or_default().get(self.size_mode)),
+                    Self::format_delta(change.delta(self.size_mode)),
+                )
+            }
+
+            None => {
+                // mount points and the like don't have a size to show
+                let size = self.fst.entries(names)
+                    .and_then(|node| node.size() )
+                    .unwrap_or_default();
+
+                Self::format_size(size.get(self.size_mode))
+            }
+        }
+    }
+
+    fn draw_line(&self, y: usize, selected: bool, marked: bool, listing: &Listing) {
+        // set colors depending on whether this line is selected or marked,
+        // greying out things that were skipped (bold black is the closest
+        // thing to grey we get with 16 colours), and picking out what's come
+        // and gone since the earlier scan
+        let (style, front, back) = if selected {
+            (rustbox::Style::empty(), rustbox::Color::Black, rustbox::Color::White)
+        } else if marked {
+            (rustbox::RB_BOLD, rustbox::Color::Yellow, rustbox::Color::Default)
+        } else if listing.kind == EntryKind::Excluded {
+            (rustbox::RB_BOLD, rustbox::Color::Black, rustbox::Color::Default)
+        } else if listing.change.map(|change| change.is_added() ).unwrap_or(false) {
+            (rustbox::Style::empty(), rustbox::Color::Green, rustbox::Color::Default)
+        } else if listing.change.map(|change| change.is_removed() ).unwrap_or(false) {
+            (rustbox::Style::empty(), rustbox::Color::Red, rustbox::Color::Default)
         } else {
-            (rustbox::Color::Default, rustbox::Color::Default)
+            (rustbox::Style::empty(), rustbox::Color::Default, rustbox::Color::Default)
         };
 
         let (name_part, size_and_dir_part) = self.format_listing(listing);
-        let size_str_x = std::cmp::max(
-            0,
-            self.rustbox.width() as i64 - size_and_dir_part.len() as i64,
-        ) as usize;
-
-        // name on the right
-        self.rustbox.print(
-            0, y, rustbox::Style::empty(),
+
+        // once anything's marked, there's a column for saying what is
+        let name_part = if self.marked.is_empty() {
+            name_part
+        } else if marked {
+            format!("* {}", name_part)
+        } else {
+            format!("  {}", name_part)
+        };
+        let size_str_x = self.rustbox.width()
+            .saturating_sub(size_and_dir_part.width());
+
+        // name on the left, squeezed if need be so there's always at least
+        // a space between it and the size
+        let name_part = Self::truncate_middle(&name_part, size_str_x.saturating_sub(1));
+        let name_end = Self::print(
+            self.rustbox, 0, y, style,
             front, back, &name_part
         );
 
         // size on the right
-        self.rustbox.print(
-            size_str_x, y, rustbox::Style::empty(),
+        Self::print(
+            self.rustbox, size_str_x, y, style,
             front, back, &size_and_dir_part
         );
 
         // and fill in the highlighted line if needed
         if selected {
-            for col in name_part.len()..size_str_x {
+            for col in name_end..size_str_x {
                 self.rustbox.print_char(
                     col, y, rustbox::Style::empty(),
                     front, back, ' '
@@ -333,30 +1254,200 @@ impl<'a> UI<'a> {
     }
 
     fn format_listing(&self, listing: &Listing) -> (String, String) {
-        let (ref name, size, is_dir, ref symlink_target) = *listing;
+        let Listing {
+            ref name, size, kind, is_dir, ref symlink_target, scanning, links, error, change
+        } = *listing;
+
+        // create the string for the size and directory indicator, with the
+        // change in size in front of it when comparing
+        let size_str = Self::format_size(size.get(self.size_mode));
+        let size_str = match change {
+            Some(change) => format!(
+                "{:>11} {:>10}", Self::format_delta(change.delta(self.size_mode)), size_str
+            ),
 
-        // create the string for the size and directory indicator
-        let size_str = Self::format_size(size);
-        let size_and_dir_part = if is_dir {
+            None => size_str,
+        };
+        let size_and_dir_part = if scanning {
+            format!("~> {:>10}", size_str)
+        } else if is_dir {
             format!("-> {:>10}", size_str)
         } else {
             format!("   {:>10}", size_str)
         };
 
-        let name_part = if let Some(ref target) = *symlink_target {
+        // the ratio column comes along automatically when sorting by it
+        let size_and_dir_part = if self.show_ratio || self.sort != SortMode::Size {
+            let ratio_str = size.ratio()
+                .map(|ratio| format!("{:.2}", ratio) )
+                .unwrap_or_else(|| String::from("-") );
+
+            format!("{:>7} {}", ratio_str, size_and_dir_part)
+        } else {
+            size_and_dir_part
+        };
+
+        let mut name_part = if let Some(ref target) = *symlink_target {
             format!(
                 "{} -> {}",
-                name.to_str().unwrap(),
-                target.to_str().unwrap()
+                self.names.show(name),
+                self.names.show(target)
             )
         } else {
-            String::from(name.to_str().unwrap())
+            self.names.show(name)
         };
 
+        // files with several hard links only count once, so it's worth
+        // pointing out which ones they are
+        if links > 1 {
+            name_part.push_str(&format!(" [{} links]", links));
+        }
+
+        match kind {
+            EntryKind::File if size.is_sparse() => name_part.push_str(" [sparse]"),
+            EntryKind::Linked => name_part.push_str(" [followed, not counted]"),
+            EntryKind::Cycle => name_part.push_str(" [cycle, not followed]"),
+            EntryKind::MountPoint => name_part.push_str(" [mount point]"),
+            EntryKind::Excluded => name_part.push_str(" [excluded]"),
+            EntryKind::Special(SpecialKind::Fifo) => name_part.push_str(" [fifo]"),
+            EntryKind::Special(SpecialKind::Socket) => name_part.push_str(" [socket]"),
+            EntryKind::Special(SpecialKind::BlockDevice) => name_part.push_str(" [block device]"),
+            EntryKind::Special(SpecialKind::CharDevice) => name_part.push_str(" [char device]"),
+            _ => (),
+        }
+
+        if let Some(error) = error {
+            name_part.push_str(&format!(" [error: {}]", error));
+        }
+
+        match change {
+            Some(change) if change.is_added() => name_part.push_str(" [new]"),
+            Some(change) if change.is_removed() => name_part.push_str(" [removed]"),
+            _ => (),
+        }
+
         (name_part, size_and_dir_part)
     }
 
-    fn format_size(size: u64) -> String {
+    /// Draw a full-screen summary of a scan that's still in progress, for
+    /// when we're waiting for it to finish before showing anything else.
+    pub fn draw_progress(
+        rustbox: &rustbox::RustBox,
+        roots: &[PathBuf],
+        progress: &Progress,
+        names: NameDisplay,
+    ) {
+        let roots: Vec<String> = roots.iter().map(|root| names.show(root) ).collect();
+
+        let lines = [
+            format!("scanning {}", roots.join(", ")),
+            String::new(),
+            format!("files:       {}", progress.files),
+            format!("directories: {}", progress.dirs),
+            format!(
+                "size:        {} ({} apparent)",
+                Self::format_size(progress.bytes.disk),
+                Self::format_size(progress.bytes.apparent),
+            ),
+            format!("errors:      {}", progress.errors),
+            String::new(),
+            Self::truncate_middle(&names.show(&progress.current), rustbox.width()),
+        ];
+
+        rustbox.clear();
+
+        for (y, line) in lines.iter().enumerate() {
+            Self::print(
+                rustbox, 0, y, rustbox::Style::empty(),
+                rustbox::Color::Default,
+                rustbox::Color::Default,
+                line
+            );
+        }
+
+        rustbox.present();
+    }
+
+    /// Print `text` starting at column `x`, going by how many columns each
+    /// character really takes up on screen, and stopping at the edge of the
+    /// screen. Returns the column just after the last thing printed.
+    fn print(
+        rustbox: &rustbox::RustBox,
+        x: usize,
+        y: usize,
+        style: rustbox::Style,
+        front: rustbox::Color,
+        back: rustbox::Color,
+        text: &str,
+    ) -> usize {
+        let mut x = x;
+
+        for c in text.chars() {
+            let width = c.width().unwrap_or(0);
+
+            // termbox has room for one character per cell, so there's
+            // nowhere to put combining marks and such
+            if width == 0 {
+                continue;
+            }
+
+            if x + width > rustbox.width() {
+                break;
+            }
+
+            rustbox.print_char(x, y, style, front, back, c);
+            x += width;
+        }
+
+        x
+    }
+
+    /// Shorten `text` to fit in `width` columns by cutting out the middle,
+    /// since the start and end of a name tend to be the telling parts.
+    pub fn truncate_middle(text: &str, width: usize) -> String {
+        if text.width() <= width {
+            return String::from(text);
+        } else if width == 0 {
+            return String::new();
+        }
+
+        // one column for the ellipsis, roughly half the rest for the start,
+        // and whatever that leaves (wide characters don't always fit
+        // exactly) for the end
+        let mut left = String::new();
+        let mut left_width = 0;
+        for c in text.chars() {
+            let c_width = c.width().unwrap_or(0);
+            if left_width + c_width > width / 2 {
+                break;
+            }
+            left.push(c);
+            left_width += c_width;
+        }
+
+        let mut right = Vec::new();
+        let mut right_width = 0;
+        for c in text.chars().rev() {
+            let c_width = c.width().unwrap_or(0);
+            if left_width + right_width + c_width > width - 1 {
+                break;
+            }
+            right.push(c);
+            right_width += c_width;
+        }
+
+        // a combining mark whose letter didn't fit would end up on the
+        // ellipsis instead
+        while right.last().and_then(|c| c.width() ) == Some(0) {
+            right.pop();
+        }
+
+        left.push('…');
+        left.extend(right.into_iter().rev());
+        left
+    }
+
+    pub fn format_size(size: u64) -> String {
         if size == 0 {
             return format!("{:>} {}", 0, 'B');
         }
@@ -372,4 +1463,89 @@ impl<'a> UI<'a> {
 
         format!("{:>.1} {}", size as f64 / (1024.0 as f64).powi(power), prefix)
     }
+
+    /// Like `format_size`, but for a change in size, so with a sign.
+    pub fn format_delta(delta: i64) -> String {
+        let size = Self::format_size(delta.unsigned_abs());
+
+        match delta.cmp(&0) {
+            Ordering::Greater => format!("+{}", size),
+            Ordering::Less => format!("-{}", size),
+            Ordering::Equal => size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    fn both(name: &[u8]) -> (String, String) {
+        let name = OsString::from_vec(name.to_vec());
+        (NameDisplay::Escape.show(&name), NameDisplay::Replace.show(&name))
+    }
+
+    #[test]
+    fn plain_names() {
+        assert_eq!(both(b"hello.txt"), ("hello.txt".to_string(), "hello.txt".to_string()));
+        assert_eq!(both("café ☃".as_bytes()), ("café ☃".to_string(), "café ☃".to_string()));
+    }
+
+    #[test]
+    fn control_characters() {
+        assert_eq!(both(b"new\nline"), ("new\\x0aline".to_string(), "new\u{fffd}line".to_string()));
+        assert_eq!(both(b"\x1b[31mred"), ("\\x1b[31mred".to_string(), "\u{fffd}[31mred".to_string()));
+        assert_eq!(both(b"del\x7f"), ("del\\x7f".to_string(), "del\u{fffd}".to_string()));
+        assert_eq!(both("c1\u{85}".as_bytes()), ("c1\\xc2\\x85".to_string(), "c1\u{fffd}".to_string()));
+    }
+
+    #[test]
+    fn backslashes() {
+        // doubled when escaping, so `\x41` the name can't be mistaken for `A`
+        assert_eq!(both(b"a\\x41"), ("a\\\\x41".to_string(), "a\\x41".to_string()));
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        assert_eq!(both(b"caf\xe9.txt"), ("caf\\xe9.txt".to_string(), "caf\u{fffd}.txt".to_string()));
+        assert_eq!(both(b"\xff\xfe"), ("\\xff\\xfe".to_string(), "\u{fffd}\u{fffd}".to_string()));
+
+        // cut off in the middle of a character
+        assert_eq!(both(b"snow\xe2\x98"), ("snow\\xe2\\x98".to_string(), "snow\u{fffd}".to_string()));
+    }
+
+    #[test]
+    fn truncating() {
+        assert_eq!(UI::truncate_middle("short", 10), "short");
+        assert_eq!(UI::truncate_middle("short", 5), "short");
+        assert_eq!(UI::truncate_middle("abcdefghij", 0), "");
+        assert_eq!(UI::truncate_middle("abcdefghij", 1), "…");
+        assert_eq!(UI::truncate_middle("abcdefghij", 5), "ab…ij");
+        assert_eq!(UI::truncate_middle("abcdefghij", 6), "abc…ij");
+    }
+
+    #[test]
+    fn truncating_wide_characters() {
+        let name = "日本語のファイル名";
+        assert_eq!(name.width(), 18);
+
+        assert_eq!(UI::truncate_middle(name, 7), "日…ル名");
+
+        // two columns at a time can't always fill the space exactly
+        let short = UI::truncate_middle(name, 6);
+        assert_eq!(short, "日…名");
+        assert!(short.width() <= 6);
+    }
+
+    #[test]
+    fn truncating_zero_width_characters() {
+        // combining marks take no room of their own, but stay with their letter
+        let name = "cafe\u{301}-cafe\u{301}";
+        assert_eq!(name.width(), 9);
+        assert_eq!(UI::truncate_middle(name, 9), name);
+        assert_eq!(UI::truncate_middle(name, 7), "caf…afe\u{301}");
+        assert_eq!(UI::truncate_middle("abcde\u{301}fg", 5), "ab…fg");
+    }
 }
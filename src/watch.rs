@@ -0,0 +1,262 @@
+// Copyright (C) 2016  Michael Dunsmuir
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate std;
+
+use std::ffi::OsString;
+use std::io;
+
+use super::fstree::FSTree;
+use super::scan::Scanner;
+
+// linux
+
+#[cfg(target_os = "linux")]
+use std::collections::{HashMap, HashSet};
+
+#[cfg(target_os = "linux")]
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+use super::fstree::Node;
+
+#[cfg(target_os = "linux")]
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+/// How long `Watcher::poll` may spend on changes before it hands control
+/// back, so that a busy directory can't starve the UI. Anything left over
+/// is picked up next time.
+#[cfg(target_os = "linux")]
+const POLL_BUDGET: Duration = Duration::from_millis(50);
+
+/// Keeps an `FSTree` in line with the filesystem by watching every
+/// directory in it for changes.
+///
+/// Entries that are created, deleted, moved or written to are read again
+/// (along with everything inside them, for directories) and put back in
+/// the tree, and the totals above them are adjusted to match.
+#[cfg(target_os = "linux")]
+pub struct Watcher<'a> {
+    inotify: Inotify,
+    scanner: Scanner<'a>,
+
+    // where each watched directory is in the tree
+    dirs: HashMap<WatchDescriptor, Vec<OsString>>,
+
+    // cleared if we ran out of watches or missed some events
+    complete: bool,
+
+    buffer: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl<'a> Watcher<'a> {
+
+    /// `scanner` is used for reading whatever changes.
+    pub fn new(scanner: Scanner<'a>) -> io::Result<Self> {
+        Inotify::init().map(|inotify|
+            Watcher {
+                inotify,
+                scanner,
+                dirs: HashMap::new(),
+                complete: true,
+                buffer: vec![0; 4096],
+            }
+        )
+    }
+
+    /// Start watching every directory in `fst`, which should be a whole
+    /// tree that's finished scanning.
+    pub fn watch(&mut self, fst: &FSTree) {
+        // a top level isn't anywhere to be watched, just what's in it
+        if fst.is_top_level() {
+            for dir in fst.root().children() {
+                self.watch_dirs(dir, &mut vec![dir.name().to_os_string()]);
+            }
+        } else {
+            self.watch_dirs(fst.root(), &mut Vec::new());
+        }
+    }
+
+    /// Start watching everything in the entry at `names` in `fst`, which
+    /// has just been put there.
+    pub fn watch_entry(&mut self, fst: &FSTree, names: &[OsString]) {
+        if let Some(entry) = fst.entries(names) {
+            self.watch_dirs(entry, &mut names.to_vec());
+        }
+    }
+
+    /// Whether every change is being picked up. This stops being the case
+    /// if the system won't give us any more watches, or if events came in
+    /// faster than we could keep up with them.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Apply whatever's changed since last time to `fst`, without waiting
+    /// for anything else. Returns `true` if the tree changed.
+    pub fn poll(&mut self, fst: &mut FSTree) -> bool {
+        let started = Instant::now();
+        let mut changed = false;
+
+        while started.elapsed() < POLL_BUDGET {
+            // the events borrow the buffer, so take what we need from them
+            // before doing anything else
+            let events: Vec<(WatchDescriptor, EventMask, Option<OsString>)> =
+                match self.inotify.read_events(&mut self.buffer) {
+                    Ok(events) => events
+                        .map(|event| (event.wd, event.mask, event.name.map(|name| name.to_os_string() )) )
+                        .collect(),
+
+                    Err(_) => break, // most likely there's nothing to read
+                };
+
+            if events.is_empty() {
+                break;
+            }
+
+            // a file being written generates a lot of these, and one look
+            // at it is enough
+            let mut modified = HashSet::new();
+
+            for (wd, mask, name) in events {
+                if mask.contains(EventMask::Q_OVERFLOW) {
+                    self.complete = false;
+                    continue;
+                }
+
+                if mask.contains(EventMask::IGNORED) {
+                    self.dirs.remove(&wd);
+                    continue;
+                }
+
+                let names = match (self.dirs.get(&wd), name) {
+                    (Some(dir), Some(name)) => {
+                        let mut names = dir.clone();
+                        names.push(name);
+                        names
+                    }
+
+                    _ => continue,
+                };
+
+                if mask.contains(EventMask::MODIFY) {
+                    if !modified.insert(names.clone()) {
+                        continue;
+                    }
+                } else {
+                    modified.remove(&names);
+                }
+
+                changed |= self.update(fst, names, mask);
+            }
+        }
+
+        changed
+    }
+
+    fn update(&mut self, fst: &mut FSTree, names: Vec<OsString>, mask: EventMask) -> bool {
+        let path = match fst.entries(&names[..names.len() - 1]).map(|dir| dir.path() ) {
+            Some(dir_path) => dir_path.join(names.last().unwrap()),
+            None => return false, // we've lost track of the directory
+        };
+
+        let entry = if mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+            None
+        } else {
+            self.scanner.scan_entry(fst.scan_root(&names), &path)
+        };
+
+        match entry {
+            Some(entry) => {
+                let inserted = fst.insert_entry(&names, entry);
+
+                // anything new in there needs watching too
+                self.watch_entry(fst, &names);
+                inserted
+            }
+
+            None => {
+                self.forget(&names);
+                fst.remove_entry(&names)
+            }
+        }
+    }
+
+    fn watch_dirs(&mut self, node: Node, names: &mut Vec<OsString>) {
+        if !node.has_contents() {
+            return;
+        }
+
+        let mask = WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVE
+            | WatchMask::MODIFY | WatchMask::ONLYDIR;
+
+        match self.inotify.watches().add(node.path(), mask) {
+            Ok(wd) => {
+                self.dirs.insert(wd, names.clone());
+            }
+
+            // most likely the watch limit; there's not much we can do
+            // about that but own up to it
+            Err(_) => self.complete = false,
+        }
+
+        for entry in node.children() {
+            names.push(entry.name().to_os_string());
+            self.watch_dirs(entry, names);
+            names.pop();
+        }
+    }
+
+    // stop watching `names` and everything inside it, which has gone away
+    fn forget(&mut self, names: &[OsString]) {
+        let gone: Vec<WatchDescriptor> = self.dirs.iter()
+            .filter(|&(_, dir)| dir.starts_with(names) )
+            .map(|(wd, _)| wd.clone() )
+            .collect();
+
+        for wd in gone {
+            self.dirs.remove(&wd);
+            let _ = self.inotify.watches().remove(wd);
+        }
+    }
+}
+
+// everywhere else... coming soon????
+
+#[cfg(not(target_os = "linux"))]
+pub struct Watcher<'a> {
+    _scanner: Scanner<'a>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl<'a> Watcher<'a> {
+
+    pub fn new(_scanner: Scanner<'a>) -> io::Result<Self> {
+        Err(io::Error::other("watching isn't supported on this platform"))
+    }
+
+    pub fn watch(&mut self, _fst: &FSTree) {}
+
+    pub fn watch_entry(&mut self, _fst: &FSTree, _names: &[OsString]) {}
+
+    pub fn is_complete(&self) -> bool {
+        false
+    }
+
+    pub fn poll(&mut self, _fst: &mut FSTree) -> bool {
+        false
+    }
+}
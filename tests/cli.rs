@@ -0,0 +1,102 @@
+// Running the dredge binary the way scripts would, for the parts of it that
+// don't need a terminal.
+
+extern crate tempdir;
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use tempdir::TempDir;
+
+// a tree with a 100KB log file in it
+fn tree() -> TempDir {
+    let tmp = TempDir::new("dredge").unwrap();
+    fs::create_dir_all(tmp.path().join("logs")).unwrap();
+    fs::write(tmp.path().join("logs/big.log"), vec![1u8; 100_000]).unwrap();
+    tmp
+}
+
+fn dredge(args: &[&str], dir: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_dredge"))
+        .arg("--apparent-size")
+        .args(args)
+        .arg(dir)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn fail_if_larger_than() {
+    let tmp = tree();
+
+    let under = dredge(&["--fail-if-larger-than", "1M"], tmp.path());
+    assert!(under.status.success(), "{:?}", under);
+    assert!(under.stdout.is_empty());
+
+    let over = dredge(&["--fail-if-larger-than", "50K"], tmp.path());
+    assert_eq!(over.status.code(), Some(1), "{:?}", over);
+    assert!(String::from_utf8_lossy(&over.stdout).contains("is over 50.0 KiB"), "{:?}", over);
+
+    let bad = dredge(&["--fail-if-larger-than", "lots"], tmp.path());
+    assert_eq!(bad.status.code(), Some(1), "{:?}", bad);
+}
+
+#[test]
+fn rules() {
+    let tmp = tree();
+    let elsewhere = TempDir::new("dredge").unwrap();
+    let rules = elsewhere.path().join("rules");
+    fs::write(&rules, "# nothing in logs should get big\nlogs/*.log > 50K\n/ > 1G\n").unwrap();
+
+    let over = dredge(&["--rules", rules.to_str().unwrap()], tmp.path());
+    assert_eq!(over.status.code(), Some(1), "{:?}", over);
+
+    let stdout = String::from_utf8_lossy(&over.stdout);
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+    assert!(stdout.contains("big.log") && stdout.contains("(logs/*.log > 50K)"), "{}", stdout);
+}
+
+#[test]
+fn unreadable() {
+    let elsewhere = TempDir::new("dredge").unwrap();
+    let dump = elsewhere.path().join("dump.json");
+    fs::write(&dump, r#"[1,2,{"progname":"ncdu","progver":"1.19"},
+[{"name":"/data"},
+{"name":"big","asize":100000,"dsize":102400},
+{"name":"locked","read_error":true}]]
+"#).unwrap();
+
+    let check = |limit: &str| Command::new(env!("CARGO_BIN_EXE_dredge"))
+        .args(["--import", dump.to_str().unwrap(), "--fail-if-larger-than", limit])
+        .output()
+        .unwrap();
+
+    let under = check("1M");
+    assert_eq!(under.status.code(), Some(2), "{:?}", under);
+    assert!(under.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&under.stderr).contains("/data/locked"), "{:?}", under);
+
+    // being over the limit is what matters most
+    let over = check("50K");
+    assert_eq!(over.status.code(), Some(1), "{:?}", over);
+    assert!(String::from_utf8_lossy(&over.stderr).contains("/data/locked"), "{:?}", over);
+}
+
+#[test]
+fn symlinked_root() {
+    let tmp = tree();
+    let elsewhere = TempDir::new("dredge").unwrap();
+    let link = elsewhere.path().join("link");
+    std::os::unix::fs::symlink(tmp.path(), &link).unwrap();
+
+    // the link is followed, so the log file inside counts
+    for args in &[&[][..], &["-H"], &["-L"]] {
+        let mut args = args.to_vec();
+        args.extend(&["--fail-if-larger-than", "50K"]);
+
+        let over = dredge(&args, &link);
+        assert_eq!(over.status.code(), Some(1), "{:?}", over);
+        assert!(over.stderr.is_empty(), "{:?}", over);
+    }
+}